@@ -0,0 +1,20 @@
+//! Records the git revision cdbd is built from, for answering version requests
+
+use std::process::Command;
+
+fn main() {
+    let revision = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .and_then(|output| if output.status.success() {
+            String::from_utf8(output.stdout).ok()
+        } else {
+            None
+        })
+        .map(|revision| revision.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=CDBD_GIT_REVISION={}", revision);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+}
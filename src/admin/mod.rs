@@ -0,0 +1,53 @@
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use kvstore::reload::ReloadableKvStore;
+
+pub mod server;
+
+/// Whether we're ready to serve, for health checks
+pub struct Health {
+    /// The database, once it's been opened
+    db: RwLock<Option<Arc<ReloadableKvStore>>>,
+    /// Whether every service is listening
+    started: AtomicBool,
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    pub fn new() -> Health {
+        Health {
+            db: RwLock::new(None),
+            started: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Note that the database has been opened, and is ready to serve.
+    pub fn loaded(&self, db: Arc<ReloadableKvStore>) {
+        *self.db.write().unwrap() = Some(db);
+    }
+
+    /// Note that every service is listening for requests.
+    pub fn started(&self) {
+        self.started.store(true, Ordering::SeqCst);
+    }
+
+    /// Note that we've started shutting down, and shouldn't be sent more work.
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Ok if we're ready to serve, or else why not
+    pub fn readiness(&self) -> Result<(), &'static str> {
+        if self.shutting_down.load(Ordering::SeqCst) {
+            return Err("shutting down");
+        }
+        match *self.db.read().unwrap() {
+            None => Err("loading database"),
+            Some(_) if !self.started.load(Ordering::SeqCst) => Err("starting services"),
+            Some(ref db) if db.is_reloading() => Err("reloading database"),
+            Some(_) => Ok(()),
+        }
+    }
+}
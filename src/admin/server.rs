@@ -0,0 +1,176 @@
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::result;
+use std::sync::Arc;
+use std::thread;
+
+use http::protocol::{Incoming, Request, Response};
+use metrics::Metrics;
+use startup::StartupError;
+use super::Health;
+
+const METRICS_PATH: &'static str = "/metrics";
+const HEALTHZ_PATH: &'static str = "/healthz";
+const READYZ_PATH: &'static str = "/readyz";
+
+/// The content type of the Prometheus text exposition format
+const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+/// Serve health checks and metrics.
+pub fn admin_server(metrics: Arc<Metrics>,
+                    health: Arc<Health>,
+                    host: &str,
+                    port: u16)
+                    -> result::Result<(), StartupError> {
+    let listener = try!(TcpListener::bind((host, port))
+        .map_err(|e| StartupError::bind("admin", host, port, e)));
+
+    // Checks and scrapes are rare, so a thread for each connection is plenty.
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let metrics = metrics.clone();
+                let health = health.clone();
+                thread::spawn(move || handle_client(&metrics, &health, stream));
+            }
+            Err(_) => {
+                trace!("connection failed as it was received");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_client(metrics: &Metrics, health: &Health, stream: TcpStream) -> Result<()> {
+    let addr = try!(stream.peer_addr());
+    trace!("admin connection from {}", addr);
+    let mut ins = BufReader::new(try!(stream.try_clone()));
+    let mut outs = BufWriter::new(stream);
+    serve(metrics, health, &mut ins, &mut outs)
+}
+
+fn serve<T: BufRead>(metrics: &Metrics,
+                     health: &Health,
+                     ins: &mut T,
+                     outs: &mut Write)
+                     -> Result<()> {
+    loop {
+        match Request::parse(ins) {
+            Incoming::Closed => break,
+            Incoming::Malformed(status, msg) => {
+                trace!("admin:malformed request: {}", msg);
+                try!(Response::new(status, msg)
+                         .header("Connection", "close")
+                         .write(outs, true));
+                try!(outs.flush());
+                break;
+            }
+            Incoming::Request(request) => {
+                let keep_alive = request.keep_alive();
+                let connection = if keep_alive { "keep-alive" } else { "close" };
+                try!(respond(metrics, health, &request)
+                         .header("Connection", connection)
+                         .write(outs, request.method != "HEAD"));
+                try!(outs.flush());
+                if !keep_alive {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn respond(metrics: &Metrics, health: &Health, request: &Request) -> Response {
+    let path = request.path();
+    if path != METRICS_PATH && path != HEALTHZ_PATH && path != READYZ_PATH {
+        trace!("admin:not found {}", path);
+        return Response::new(404, "");
+    }
+    if request.method != "GET" && request.method != "HEAD" {
+        trace!("admin:method not allowed {}", request.method);
+        return Response::new(405, "").header("Allow", "GET, HEAD");
+    }
+    if path == METRICS_PATH {
+        Response::new(200, metrics.render()).header("Content-Type", CONTENT_TYPE)
+    } else if path == HEALTHZ_PATH {
+        // If we can answer at all, we're alive.
+        Response::new(200, "ok\n").header("Content-Type", "text/plain")
+    } else {
+        match health.readiness() {
+            Ok(()) => Response::new(200, "ok\n"),
+            Err(reason) => {
+                trace!("admin:not ready: {}", reason);
+                Response::new(503, format!("{}\n", reason))
+            }
+        }
+        .header("Content-Type", "text/plain")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use kvstore::{KvStore, Value};
+    use kvstore::reload::ReloadableKvStore;
+    use metrics::{Metrics, Outcome};
+    use super::super::Health;
+
+    /// An empty KvStore
+    struct DummyKvStore {}
+
+    impl KvStore for DummyKvStore {
+        fn get(&self, _key: &[u8]) -> Option<Value> {
+            None
+        }
+    }
+
+    fn get(health: &Health, path: &str) -> String {
+        let request = format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path);
+        let mut outs = Vec::new();
+        super::serve(&Metrics::new(), health, &mut Cursor::new(request.as_bytes()), &mut outs)
+            .unwrap();
+        String::from_utf8(outs).unwrap()
+    }
+
+    #[test]
+    fn test_health() {
+        let health = Health::new();
+        assert!(get(&health, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+        let readyz = get(&health, "/readyz");
+        assert!(readyz.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(readyz.ends_with("\r\n\r\nloading database\n"));
+        let db = ReloadableKvStore::new(|| {
+                Ok(Arc::new(DummyKvStore {}) as Arc<KvStore + Send + Sync>)
+            })
+            .unwrap();
+        health.loaded(Arc::new(db));
+        assert!(get(&health, "/readyz").ends_with("\r\n\r\nstarting services\n"));
+        health.started();
+        assert!(get(&health, "/readyz").starts_with("HTTP/1.1 200 OK\r\n"));
+        health.shut_down();
+        assert!(get(&health, "/readyz").ends_with("\r\n\r\nshutting down\n"));
+        assert!(get(&health, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let health = Health::new();
+        let metrics = Metrics::new();
+        metrics.record("memcached", "getk", Outcome::Miss);
+        let mut ins = Cursor::new("GET /metrics HTTP/1.1\r\n\r\nGET /nope HTTP/1.1\r\n\
+                                   Connection: close\r\n\r\n"
+            .as_bytes());
+        let mut outs = Vec::new();
+        super::serve(&metrics, &health, &mut ins, &mut outs).unwrap();
+        let response = String::from_utf8(outs).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.contains("cdbd_requests_total{protocol=\"memcached\",\
+                                    command=\"getk\",outcome=\"miss\"} 1\n"));
+        assert!(response.ends_with("HTTP/1.1 404 Not Found\r\nConnection: close\r\n\
+                                    Content-Length: 0\r\n\r\n"));
+    }
+}
@@ -0,0 +1,509 @@
+//! Reading what to serve from a TOML config file, as an alternative to flags
+//!
+//! A config file lists databases as `[[database]]` tables, services as `[[service]]` tables, and
+//! optional `[logging]` and `[tuning]` tables; README.md has an example.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::Duration;
+
+use toml;
+use toml::Spanned;
+
+use kvstore::sqlite::SqliteQuery;
+use memcached::connections::{ConnectionLimit, OverLimit};
+use memcached::server::MemcachedOptions;
+use num_cpus;
+use {db_types, default_pool_size, parse_listen, Args, DbArg, Listen, ServiceArg,
+     DEFAULT_DRAIN_TIMEOUT_SECS};
+
+/// What's wrong with a config file, saying where in it when we can
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// A value that marks a key as deleted in an overlay
+    tombstone: Option<Spanned<String>>,
+    #[serde(default)]
+    database: Vec<DatabaseConfig>,
+    /// Databases layered over the database, consulted in order
+    #[serde(default)]
+    overlay: Vec<DatabaseConfig>,
+    #[serde(default)]
+    service: Vec<ServiceConfig>,
+    #[serde(default)]
+    logging: LoggingConfig,
+    #[serde(default)]
+    tuning: TuningConfig,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DatabaseConfig {
+    /// The type of database, named as in its flag
+    #[serde(rename = "type")]
+    kind: Spanned<String>,
+    path: String,
+    /// Serve the database's keys prefixed with "NAMESPACE:"
+    namespace: Option<Spanned<String>>,
+    /// The named database to serve from an LMDB environment
+    db: Option<Spanned<String>>,
+    /// How to look keys up in a SQLite database
+    table: Option<Spanned<String>>,
+    key_column: Option<Spanned<String>>,
+    value_column: Option<Spanned<String>>,
+    query: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Protocol {
+    Memcached,
+    #[serde(rename = "memcached-udp")]
+    MemcachedUdp,
+    Redis,
+    Http,
+    /// Health checks and metrics
+    Admin,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum OverLimitConfig {
+    Queue,
+    Reject,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ServiceConfig {
+    protocol: Spanned<Protocol>,
+    /// "[HOST:]PORT"
+    listen: Spanned<String>,
+    /// Memcached's options, as for its flags
+    threads: Option<Spanned<usize>>,
+    max_connections: Option<Spanned<usize>>,
+    over_limit: Option<Spanned<OverLimitConfig>>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum Level {
+    Warn,
+    Info,
+    Trace,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoggingConfig {
+    level: Level,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> LoggingConfig {
+        LoggingConfig { level: Level::Warn }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct TuningConfig {
+    /// How many handles to open for databases that need one per concurrent lookup
+    pool_size: Option<Spanned<usize>>,
+    /// Seconds to let connections finish their requests once told to shut down
+    drain_timeout: Option<Spanned<usize>>,
+}
+
+/// Read the databases, services and tuning to run with from a config file.
+pub fn read_config(path: &Path) -> Result<Args, ConfigError> {
+    let mut text = String::new();
+    try!(File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut text))
+        .map_err(|e| ConfigError(e.to_string())));
+    parse_config(&text)
+}
+
+/// Parse a config file's text.
+pub fn parse_config(text: &str) -> Result<Args, ConfigError> {
+    let config: Config = try!(toml::from_str(text).map_err(|e| ConfigError(e.to_string())));
+    let parser = Parser { text: text };
+    let db = try!(parser.database(config.database, config.overlay, config.tombstone));
+    let mut services = Vec::new();
+    let mut admin = Vec::new();
+    for service in config.service {
+        let listen = try!(parser.listen(&service.listen));
+        match *service.protocol.get_ref() {
+            Protocol::Memcached => {
+                let options = try!(parser.memcached_options(&service));
+                services.push(ServiceArg::Memcached(listen, options));
+            }
+            Protocol::MemcachedUdp => {
+                let message = "UDP services have no connections to limit";
+                try!(parser.forbid(&service.max_connections, message));
+                try!(parser.forbid(&service.over_limit, message));
+                let options = try!(parser.memcached_options(&service));
+                services.push(ServiceArg::MemcachedUdp(listen, options));
+            }
+            protocol => {
+                let message = "only memcached services have this option";
+                try!(parser.forbid(&service.threads, message));
+                try!(parser.forbid(&service.max_connections, message));
+                try!(parser.forbid(&service.over_limit, message));
+                match protocol {
+                    Protocol::Redis => services.push(ServiceArg::Redis(listen)),
+                    Protocol::Http => services.push(ServiceArg::Http(listen)),
+                    _ => admin.push(listen),
+                }
+            }
+        }
+    }
+    if services.is_empty() {
+        return Err(ConfigError("no [[service]] to run, other than admin ones".to_string()));
+    }
+    Ok(Args {
+        db: db,
+        services: services,
+        admin: admin,
+        pool_size: try!(parser.count(&config.tuning.pool_size, "pool_size"))
+            .unwrap_or_else(default_pool_size),
+        drain_timeout: Duration::from_secs(try!(parser.count(&config.tuning.drain_timeout,
+                                                             "drain_timeout"))
+                                               .map_or(DEFAULT_DRAIN_TIMEOUT_SECS,
+                                                       |n| n as u64)),
+        verbosity: match config.logging.level {
+            Level::Warn => 0,
+            Level::Info => 1,
+            Level::Trace => 2,
+        },
+    })
+}
+
+/// Turns a config file's tables into args, pointing errors at where they are in its text
+struct Parser<'a> {
+    text: &'a str,
+}
+
+impl<'a> Parser<'a> {
+    /// An error about a value, saying where it is as toml's own errors do
+    fn error<T>(&self, value: &Spanned<T>, message: String) -> ConfigError {
+        let before = &self.text[..value.start()];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+        ConfigError(format!("{} at line {} column {}", message, line, column))
+    }
+
+    /// Fail if `option` is given where it doesn't belong.
+    fn forbid<T>(&self, option: &Option<Spanned<T>>, message: &str) -> Result<(), ConfigError> {
+        match option {
+            &Some(ref value) => Err(self.error(value, message.to_string())),
+            &None => Ok(()),
+        }
+    }
+
+    fn count(&self, n: &Option<Spanned<usize>>, what: &str) -> Result<Option<usize>, ConfigError> {
+        match n {
+            &Some(ref n) if *n.get_ref() == 0 => {
+                Err(self.error(n, format!("{} must be more than 0", what)))
+            }
+            &Some(ref n) => Ok(Some(*n.get_ref())),
+            &None => Ok(None),
+        }
+    }
+
+    fn listen(&self, listen: &Spanned<String>) -> Result<Listen, ConfigError> {
+        parse_listen(listen.get_ref()).map_err(|e| self.error(listen, e))
+    }
+
+    fn memcached_options(&self, service: &ServiceConfig) -> Result<MemcachedOptions, ConfigError> {
+        let over_limit = match service.over_limit.as_ref().map(|o| *o.get_ref()) {
+            None | Some(OverLimitConfig::Queue) => OverLimit::Queue,
+            Some(OverLimitConfig::Reject) => OverLimit::Reject,
+        };
+        let max = try!(self.count(&service.max_connections, "max_connections"));
+        if max.is_none() {
+            try!(self.forbid(&service.over_limit, "over_limit needs max_connections"));
+        }
+        Ok(MemcachedOptions {
+            threads: try!(self.count(&service.threads, "threads")).unwrap_or_else(num_cpus::get),
+            connection_limit: max.map(|max| {
+                ConnectionLimit {
+                    max: max,
+                    over_limit: over_limit,
+                }
+            }),
+        })
+    }
+
+    /// The database to serve: one database, or several under namespaces, maybe with overlays
+    fn database(&self,
+                databases: Vec<DatabaseConfig>,
+                overlays: Vec<DatabaseConfig>,
+                tombstone: Option<Spanned<String>>)
+                -> Result<DbArg, ConfigError> {
+        let base = match databases.len() {
+            0 => return Err(ConfigError("no [[database]] to serve".to_string())),
+            1 if databases[0].namespace.is_none() => {
+                try!(self.typed_db(databases.into_iter().next().unwrap()))
+            }
+            _ => {
+                let mut namespaces: Vec<(String, DbArg)> = Vec::new();
+                for database in databases {
+                    let name = match database.namespace.clone() {
+                        Some(name) => name,
+                        None => {
+                            return Err(self.error(&database.kind,
+                                                  "each of several databases needs a namespace"
+                                                      .to_string()))
+                        }
+                    };
+                    if namespaces.iter().any(|&(ref n, _)| n == name.get_ref()) {
+                        return Err(self.error(&name,
+                                              format!("database namespace \"{}\" given more \
+                                                       than once",
+                                                      name.get_ref())));
+                    }
+                    namespaces.push((name.into_inner(), try!(self.typed_db(database))));
+                }
+                DbArg::Namespaced(namespaces)
+            }
+        };
+        if overlays.is_empty() && tombstone.is_none() {
+            return Ok(base);
+        }
+        if let DbArg::Namespaced(_) = base {
+            let message = "overlays and tombstones need a single database without a namespace";
+            try!(self.forbid(&tombstone, message));
+            return Err(self.error(&overlays[0].kind, message.to_string()));
+        }
+        let mut layers = Vec::with_capacity(overlays.len() + 1);
+        for overlay in overlays {
+            try!(self.forbid(&overlay.namespace, "overlays can't have a namespace"));
+            layers.push(try!(self.typed_db(overlay)));
+        }
+        layers.push(base);
+        Ok(DbArg::Overlay {
+            layers: layers,
+            tombstone: tombstone.map(|t| t.into_inner().into_bytes()),
+        })
+    }
+
+    /// One database file, with the options for its type
+    fn typed_db(&self, database: DatabaseConfig) -> Result<DbArg, ConfigError> {
+        let db_f = try!(db_types()
+            .into_iter()
+            .find(|&(name, _)| name == database.kind.get_ref())
+            .map(|(_, db_f)| db_f)
+            .ok_or_else(|| {
+                self.error(&database.kind,
+                           format!("unknown database type \"{}\"", database.kind.get_ref()))
+            }));
+        let sqlite_options =
+            [&database.table, &database.key_column, &database.value_column, &database.query];
+        match db_f(database.path.clone()) {
+            DbArg::Lmdb(f, _) => {
+                for option in sqlite_options.iter() {
+                    try!(self.forbid(option, "only sqlite databases have this option"));
+                }
+                Ok(DbArg::Lmdb(f, database.db.as_ref().map(|db| db.get_ref().clone())))
+            }
+            DbArg::Sqlite(f, _) => {
+                try!(self.forbid(&database.db, "only lmdb databases have this option"));
+                Ok(DbArg::Sqlite(f, try!(self.sqlite_query(&database))))
+            }
+            db => {
+                try!(self.forbid(&database.db, "only lmdb databases have this option"));
+                for option in sqlite_options.iter() {
+                    try!(self.forbid(option, "only sqlite databases have this option"));
+                }
+                Ok(db)
+            }
+        }
+    }
+
+    /// How to look keys up in a SQLite database, as for the --sqlite-* flags
+    fn sqlite_query(&self, database: &DatabaseConfig) -> Result<SqliteQuery, ConfigError> {
+        if let Some(ref sql) = database.query {
+            for option in [&database.table, &database.key_column, &database.value_column].iter() {
+                try!(self.forbid(option, "a query replaces this option"));
+            }
+            return Ok(SqliteQuery::Select(sql.get_ref().clone()));
+        }
+        let or = |option: &Option<Spanned<String>>, default: &str| {
+            option.as_ref().map_or(default.to_string(), |o| o.get_ref().clone())
+        };
+        Ok(SqliteQuery::Table {
+            table: or(&database.table, "kv"),
+            key_column: or(&database.key_column, "key"),
+            value_column: or(&database.value_column, "value"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use kvstore::sqlite::SqliteQuery;
+    use memcached::connections::OverLimit;
+    use {DbArg, ServiceArg};
+
+    use super::parse_config;
+
+    #[test]
+    fn test_parse_config() {
+        let args = parse_config(r#"
+tombstone = "DELETED"
+
+[[database]]
+type = "sqlite"
+path = "base.sqlite"
+table = "things"
+
+[[overlay]]
+type = "cdb"
+path = "changes.cdb"
+
+[[service]]
+protocol = "memcached"
+listen = "127.0.0.1:11211"
+threads = 2
+max_connections = 100
+over_limit = "reject"
+
+[[service]]
+protocol = "http"
+listen = "8080"
+
+[[service]]
+protocol = "memcached-udp"
+listen = "11211"
+threads = 3
+
+[[service]]
+protocol = "admin"
+listen = "9090"
+
+[logging]
+level = "info"
+
+[tuning]
+pool_size = 4
+drain_timeout = 5
+"#)
+            .unwrap();
+        match args.db {
+            DbArg::Overlay { ref layers, ref tombstone } => {
+                assert_eq!(Some(b"DELETED".to_vec()), *tombstone);
+                match (&layers[0], &layers[1]) {
+                    (&DbArg::Cdb(ref overlay), &DbArg::Sqlite(ref base, SqliteQuery::Table {
+                        ref table, ref key_column, ..
+                    })) => {
+                        assert_eq!(("changes.cdb", "base.sqlite"),
+                                   (overlay.as_str(), base.as_str()));
+                        assert_eq!(("things", "key"), (table.as_str(), key_column.as_str()));
+                    }
+                    _ => panic!("unexpected layers {:?}", layers),
+                }
+            }
+            ref db => panic!("unexpected database {:?}", db),
+        }
+        match (&args.services[0], &args.services[1]) {
+            (&ServiceArg::Memcached(ref listen, ref options), &ServiceArg::Http(ref http)) => {
+                assert_eq!("127.0.0.1:11211", listen.to_string());
+                assert_eq!(2, options.threads);
+                let limit = options.connection_limit.unwrap();
+                assert_eq!((100, OverLimit::Reject), (limit.max, limit.over_limit));
+                assert_eq!("0.0.0.0:8080", http.to_string());
+            }
+            _ => panic!("unexpected services {:?}", args.services),
+        }
+        match &args.services[2] {
+            &ServiceArg::MemcachedUdp(ref listen, ref options) => {
+                assert_eq!(("0.0.0.0:11211".to_string(), 3),
+                           (listen.to_string(), options.threads));
+            }
+            service => panic!("unexpected service {:?}", service),
+        }
+        assert_eq!(vec!["0.0.0.0:9090".to_string()],
+                   args.admin.iter().map(|l| l.to_string()).collect::<Vec<_>>());
+        assert_eq!((4, Duration::from_secs(5), 1),
+                   (args.pool_size, args.drain_timeout, args.verbosity));
+    }
+
+    #[test]
+    fn test_namespaces() {
+        let args = parse_config(r#"
+[[database]]
+type = "cdb"
+path = "a.cdb"
+namespace = "a"
+
+[[database]]
+type = "lmdb"
+path = "b"
+namespace = "b"
+db = "things"
+
+[[service]]
+protocol = "redis"
+listen = "6379"
+"#)
+            .unwrap();
+        match args.db {
+            DbArg::Namespaced(ref dbs) => {
+                assert_eq!(vec!["a", "b"], dbs.iter().map(|d| d.0.as_str()).collect::<Vec<_>>());
+                match dbs[1].1 {
+                    DbArg::Lmdb(_, Some(ref name)) => assert_eq!("things", name),
+                    ref db => panic!("unexpected database {:?}", db),
+                }
+            }
+            ref db => panic!("unexpected database {:?}", db),
+        }
+        assert_eq!(0, args.verbosity);
+    }
+
+    fn error(text: &str) -> String {
+        parse_config(text).err().expect("config should be invalid").to_string()
+    }
+
+    #[test]
+    fn test_errors() {
+        let service = "\n[[service]]\nprotocol = \"http\"\nlisten = \"80\"\n";
+        assert_eq!("unknown database type \"cbd\" at line 2 column 8",
+                   error(&format!("[[database]]\ntype = \"cbd\"\npath = \"a\"\n{}", service)));
+        assert_eq!("database namespace \"a\" given more than once at line 7 column 13",
+                   error(&format!("[[database]]\ntype = \"cdb\"\npath = \"a\"\nnamespace = \
+                                   \"a\"\n[[database]]\ntype = \"cdb\"\nnamespace = \"a\"\npath \
+                                   = \"b\"\n{}",
+                                  service)));
+        assert_eq!("only sqlite databases have this option at line 4 column 9",
+                   error(&format!("[[database]]\ntype = \"cdb\"\npath = \"a\"\ntable = \"t\"\n{}",
+                                  service)));
+        assert_eq!("error parsing port from \"99999\" at line 6 column 10",
+                   error("[[database]]\ntype = \"cdb\"\npath = \"a\"\n[[service]]\nprotocol = \
+                          \"http\"\nlisten = \"99999\"\n"));
+        assert_eq!("threads must be more than 0 at line 7 column 11",
+                   error("[[database]]\ntype = \"cdb\"\npath = \"a\"\n[[service]]\nprotocol = \
+                          \"memcached\"\nlisten = \"1\"\nthreads = 0\n"));
+        assert_eq!("UDP services have no connections to limit at line 7 column 19",
+                   error("[[database]]\ntype = \"cdb\"\npath = \"a\"\n[[service]]\nprotocol = \
+                          \"memcached-udp\"\nlisten = \"1\"\nmax_connections = 5\n"));
+        assert!(error(&format!("[[database]]\ntype = \"cdb\"\npath = \"a\"\ncolor = 1\n{}",
+                               service))
+                    .contains("unknown field `color`"));
+        assert!(error(&format!("[[database]]\ntype = \"cdb\"\n{}", service))
+                    .contains("missing field `path`"));
+        assert_eq!("no [[database]] to serve", error(service));
+    }
+}
@@ -0,0 +1,239 @@
+//! A minimal HTTP/1.1 implementation, as described at https://tools.ietf.org/html/rfc7230
+
+use std::io::{BufRead, Read, Result, Write};
+
+use kvstore::Value;
+
+/// The most headers we'll accept in one request
+const MAX_HEADERS: usize = 100;
+/// The longest request body we'll accept
+const MAX_BODY_LENGTH: u64 = 64 * 1024 * 1024;
+
+/// An HTTP request
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    /// The minor version, as in HTTP/1.x
+    pub minor_version: u8,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+/// The result of reading a request from a connection
+#[derive(Debug)]
+pub enum Incoming {
+    Request(Request),
+    /// A request we can't serve, with the status to answer it with; the connection can't be
+    /// trusted to be in sync afterward
+    Malformed(u16, String),
+    Closed,
+}
+
+/// An HTTP response
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    /// The body, which may be a value straight out of the database
+    pub body: Value,
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// Read one line, stripping the trailing "\r\n" (or bare "\n").
+fn read_line(rdr: &mut BufRead) -> Result<Option<String>> {
+    let mut line = String::new();
+    match try!(rdr.read_line(&mut line)) {
+        0 => Ok(None),
+        _ => {
+            while line.ends_with('\n') || line.ends_with('\r') {
+                line.pop();
+            }
+            Ok(Some(line))
+        }
+    }
+}
+
+fn parse_request_line(line: &str) -> Option<(String, String, u8)> {
+    let elts: Vec<&str> = line.split(' ').collect();
+    match (elts.len(), elts.get(2).map(|v| v.trim_left_matches("HTTP/1."))) {
+        (3, Some(minor)) if elts[2].starts_with("HTTP/1.") => {
+            minor.parse().ok().map(|minor| (elts[0].to_string(), elts[1].to_string(), minor))
+        }
+        _ => None,
+    }
+}
+
+impl Request {
+    pub fn parse(rdr: &mut BufRead) -> Incoming {
+        match Request::read(rdr) {
+            Err(_) => Incoming::Closed,
+            Ok(incoming) => incoming,
+        }
+    }
+
+    fn read(rdr: &mut BufRead) -> Result<Incoming> {
+        // Clients may send stray empty lines between requests.
+        let mut line = String::new();
+        while line.is_empty() {
+            line = match try!(read_line(rdr)) {
+                Some(line) => line,
+                None => return Ok(Incoming::Closed),
+            };
+        }
+        let (method, target, minor_version) = match parse_request_line(&line) {
+            Some(parts) => parts,
+            None => return Ok(Incoming::Malformed(400, format!("bad request line {:?}", line))),
+        };
+        let mut headers = Vec::new();
+        loop {
+            let line = match try!(read_line(rdr)) {
+                Some(line) => line,
+                None => return Ok(Incoming::Closed),
+            };
+            if line.is_empty() {
+                break;
+            }
+            if headers.len() >= MAX_HEADERS {
+                return Ok(Incoming::Malformed(400, "too many headers".to_string()));
+            }
+            match line.find(':') {
+                Some(i) => {
+                    headers.push((line[..i].trim().to_string(), line[i + 1..].trim().to_string()))
+                }
+                None => return Ok(Incoming::Malformed(400, format!("bad header {:?}", line))),
+            }
+        }
+        let mut request = Request {
+            method: method,
+            target: target,
+            minor_version: minor_version,
+            headers: headers,
+            body: Vec::new(),
+        };
+        if request.header("transfer-encoding").is_some() {
+            return Ok(Incoming::Malformed(400, "chunked requests are not supported".to_string()));
+        }
+        let length = match request.header("content-length").map(|v| v.parse::<u64>()) {
+            None => 0,
+            Some(Ok(length)) if length <= MAX_BODY_LENGTH => length,
+            Some(Ok(_)) => return Ok(Incoming::Malformed(413, "request body too long".to_string())),
+            Some(Err(_)) => return Ok(Incoming::Malformed(400, "bad content length".to_string())),
+        };
+        try!(rdr.take(length).read_to_end(&mut request.body));
+        if (request.body.len() as u64) < length {
+            return Ok(Incoming::Closed);
+        }
+        Ok(Incoming::Request(request))
+    }
+
+    /// Find the value of a header, by case-insensitive name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    /// Whether the client wants the connection kept open after this request
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("connection").map(|v| v.to_ascii_lowercase());
+        match (self.minor_version, connection.as_ref().map(|v| v.as_str())) {
+            (_, Some("close")) => false,
+            (0, Some("keep-alive")) => true,
+            (0, _) => false,
+            _ => true,
+        }
+    }
+
+    /// The request target's path, without any query string
+    pub fn path(&self) -> &str {
+        self.target.split('?').next().unwrap_or("")
+    }
+
+    /// The still percent-encoded value of the named query string parameter
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.target
+            .splitn(2, '?')
+            .nth(1)
+            .unwrap_or("")
+            .split('&')
+            .map(|param| {
+                let mut parts = param.splitn(2, '=');
+                (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+            })
+            .find(|&(n, _)| n == name)
+            .map(|(_, value)| value)
+    }
+}
+
+/// Decode a percent-encoded URL component, or None if it's malformed.
+pub fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                // from_str_radix would also take a sign, as in "%+1"
+                let hex = match bytes.get(i + 1..i + 3) {
+                    Some(hex) if hex.iter().all(|c| c.is_ascii_hexdigit()) => hex,
+                    _ => return None,
+                };
+                match u8::from_str_radix(&String::from_utf8_lossy(hex), 16) {
+                    Ok(b) => decoded.push(b),
+                    Err(_) => return None,
+                }
+                i += 3;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Some(decoded)
+}
+
+impl Response {
+    pub fn new<B: Into<Value>>(status: u16, body: B) -> Response {
+        Response {
+            status: status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Response {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    /// Write the response; for HEAD requests, leave off the body but keep its Content-Length.
+    pub fn write(&self, wtr: &mut Write, include_body: bool) -> Result<()> {
+        try!(write!(wtr, "HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status)));
+        for &(name, ref value) in self.headers.iter() {
+            try!(write!(wtr, "{}: {}\r\n", name, value));
+        }
+        try!(write!(wtr, "Content-Length: {}\r\n\r\n", self.body.len()));
+        if include_body {
+            try!(wtr.write_all(&self.body));
+        }
+        Ok(())
+    }
+}
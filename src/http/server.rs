@@ -0,0 +1,660 @@
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Bound;
+use std::result;
+use std::sync::Arc;
+use std::thread;
+
+use base64;
+use byteorder::{BigEndian, WriteBytesExt};
+use serde_json;
+
+use kvstore::{prefix_end, KvStore, SortedKvStore, Value};
+use metrics::{Metrics, Outcome};
+use metrics::connections::{ConnectionMetrics, Counted};
+use startup::StartupError;
+use super::protocol::{percent_decode, Incoming, Request, Response};
+
+/// The protocol name to report in metrics
+const PROTOCOL: &'static str = "http";
+
+const KEYS_PATH: &'static str = "/v1/keys/";
+const MGET_PATH: &'static str = "/v1/mget";
+const PREFIX_PATH: &'static str = "/v1/prefix/";
+
+/// How many pairs a prefix request gets by default, and the most it may ask for
+const DEFAULT_PREFIX_LIMIT: usize = 100;
+const MAX_PREFIX_LIMIT: usize = 1000;
+
+/// The value length that marks a missing key in a binary mget response
+const MISSING_VALUE_LENGTH: u32 = 0xffffffff;
+
+/// Serve HTTP on `host`:`port`, calling `bound` once it's listening.
+pub fn http_server<KV>(kvstore: KV,
+                       host: &str,
+                       port: u16,
+                       metrics: Arc<Metrics>,
+                       bound: &Fn())
+                       -> result::Result<(), StartupError>
+    where KV: KvStore,
+          KV: Clone,
+          KV: Send,
+          KV: 'static
+{
+    let listener = try!(TcpListener::bind((host, port))
+        .map_err(|e| StartupError::bind("http", host, port, e)));
+    bound();
+    let connections = ConnectionMetrics::register(PROTOCOL, &metrics);
+
+    // accept connections and process them, spawning a new thread for each one
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                // connection succeeded
+                let kvs = kvstore.clone();
+                let metrics = metrics.clone();
+                let connections = connections.clone();
+                thread::spawn(move || handle_client(kvs, &metrics, &connections, stream));
+            }
+            Err(_) => {
+                trace!("connection failed as it was received");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_client<KV: KvStore>(kvstore: KV,
+                              metrics: &Metrics,
+                              connections: &Arc<ConnectionMetrics>,
+                              stream: TcpStream)
+                              -> Result<()> {
+    let addr = try!(stream.peer_addr());
+    info!("http connection from {}", addr);
+    let _open = ConnectionMetrics::open(connections);
+    let mut ins = BufReader::new(Counted::new(try!(stream.try_clone()), connections.bytes()));
+    let mut outs = BufWriter::new(Counted::new(stream, connections.bytes()));
+    let result = serve(kvstore, metrics, &mut ins, &mut outs);
+    info!("http disconnection from {}", addr);
+    result
+}
+
+fn serve<KV: KvStore, T: BufRead>(kvstore: KV,
+                                  metrics: &Metrics,
+                                  ins: &mut T,
+                                  outs: &mut Write)
+                                  -> Result<()> {
+    loop {
+        match Request::parse(ins) {
+            Incoming::Closed => break,
+            Incoming::Malformed(status, msg) => {
+                trace!("http:malformed request: {}", msg);
+                metrics.record(PROTOCOL, "invalid", Outcome::Error);
+                try!(Response::new(status, msg)
+                         .header("Connection", "close")
+                         .write(outs, true));
+                try!(outs.flush());
+                break;
+            }
+            Incoming::Request(request) => {
+                let keep_alive = request.keep_alive();
+                try!(respond(&kvstore, metrics, &request, keep_alive, outs));
+                try!(outs.flush());
+                if !keep_alive {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn respond<KV: KvStore>(kvstore: &KV,
+                        metrics: &Metrics,
+                        request: &Request,
+                        keep_alive: bool,
+                        outs: &mut Write)
+                        -> Result<()> {
+    let include_body = request.method != "HEAD";
+    let connection = if keep_alive { "keep-alive" } else { "close" };
+    let path = request.path();
+    let response = if path.starts_with(KEYS_PATH) {
+        respond_key(kvstore, metrics, request, &path[KEYS_PATH.len()..])
+    } else if path == MGET_PATH {
+        respond_mget(kvstore, metrics, request)
+    } else if path.starts_with(PREFIX_PATH) {
+        respond_prefix(kvstore, metrics, request, &path[PREFIX_PATH.len()..])
+    } else {
+        trace!("http:not found {}", path);
+        metrics.record(PROTOCOL, "unknown", Outcome::Error);
+        Response::new(404, "")
+    };
+    response.header("Connection", connection).write(outs, include_body)
+}
+
+fn respond_key<KV: KvStore>(kvstore: &KV,
+                            metrics: &Metrics,
+                            request: &Request,
+                            encoded_key: &str)
+                            -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        trace!("http:method not allowed {}", request.method);
+        metrics.record(PROTOCOL, "get", Outcome::Error);
+        return Response::new(405, "").header("Allow", "GET, HEAD");
+    }
+    let key = match percent_decode(encoded_key) {
+        Some(key) => key,
+        None => {
+            trace!("http:bad key encoding {}", encoded_key);
+            metrics.record(PROTOCOL, "get", Outcome::Error);
+            return Response::new(400, "bad percent-encoding in key");
+        }
+    };
+    match lookup(kvstore, metrics, "get", &key) {
+        Some(value) => {
+            trace!("http:get {:?} => {} bytes", key, value.len());
+            Response::new(200, value).header("Content-Type", "application/octet-stream")
+        }
+        None => {
+            trace!("http:get {:?} => not found", key);
+            Response::new(404, "")
+        }
+    }
+}
+
+/// Look a key up, counting it in the metrics as a hit or miss of `command`.
+fn lookup<KV: KvStore>(kvstore: &KV,
+                       metrics: &Metrics,
+                       command: &'static str,
+                       key: &[u8])
+                       -> Option<Value> {
+    let value = metrics.time_lookup(PROTOCOL, || kvstore.get(key));
+    metrics.record(PROTOCOL,
+                   command,
+                   if value.is_some() {
+                       Outcome::Hit
+                   } else {
+                       Outcome::Miss
+                   });
+    value
+}
+
+/// One key's result in a JSON mget response, with the key and value in base64
+#[derive(Serialize)]
+struct MgetEntry {
+    key: String,
+    found: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+/// One pair in a JSON prefix response, with the key and value in base64
+#[derive(Serialize)]
+struct PrefixEntry {
+    key: String,
+    value: String,
+}
+
+/// A page of a JSON prefix response, with the base64 key to list the next page after, if there
+/// is one
+#[derive(Serialize)]
+struct PrefixPage {
+    pairs: Vec<PrefixEntry>,
+    next: Option<String>,
+}
+
+/// Read the keys of an mget request: a JSON array of base64 strings, or else one key per line.
+fn parse_mget_keys(request: &Request) -> result::Result<Vec<Vec<u8>>, String> {
+    let json = request.header("content-type").map_or(false, |t| t.starts_with("application/json"));
+    if json {
+        let keys = try!(serde_json::from_slice::<Vec<String>>(&request.body)
+            .map_err(|e| format!("bad JSON key list: {}", e)));
+        keys.iter()
+            .map(|key| base64::decode(key).map_err(|_| format!("bad base64 key {:?}", key)))
+            .collect()
+    } else {
+        Ok(request.body
+               .split(|&c| c == b'\n')
+               .map(|line| if line.ends_with(b"\r") {
+                   &line[..line.len() - 1]
+               } else {
+                   line
+               })
+               .filter(|key| !key.is_empty())
+               .map(|key| key.to_vec())
+               .collect())
+    }
+}
+
+fn respond_mget<KV: KvStore>(kvstore: &KV, metrics: &Metrics, request: &Request) -> Response {
+    if request.method != "POST" {
+        trace!("http:method not allowed {}", request.method);
+        metrics.record(PROTOCOL, "mget", Outcome::Error);
+        return Response::new(405, "").header("Allow", "POST");
+    }
+    let keys = match parse_mget_keys(request) {
+        Ok(keys) => keys,
+        Err(msg) => {
+            trace!("http:mget {}", msg);
+            metrics.record(PROTOCOL, "mget", Outcome::Error);
+            return Response::new(400, msg);
+        }
+    };
+    trace!("http:mget {} keys", keys.len());
+    let accept = request.header("accept").unwrap_or("*/*");
+    let binary = accept.contains("application/octet-stream");
+    if !binary && !accept.contains("application/json") && !accept.contains("*/*") {
+        trace!("http:mget not acceptable {}", accept);
+        metrics.record(PROTOCOL, "mget", Outcome::Error);
+        return Response::new(406,
+                             "supported types are application/json and \
+                              application/octet-stream");
+    }
+    let values = keys.iter().map(|key| lookup(kvstore, metrics, "mget", key));
+    if binary {
+        // Each key is framed as <u32 key length><key><u32 value length><value>, with a value
+        // length of 0xffffffff for missing keys.
+        let mut body = Vec::new();
+        for (key, value) in keys.iter().zip(values) {
+            body.write_u32::<BigEndian>(key.len() as u32).unwrap();
+            body.extend_from_slice(key);
+            match value {
+                Some(value) => {
+                    body.write_u32::<BigEndian>(value.len() as u32).unwrap();
+                    body.extend_from_slice(&value);
+                }
+                None => body.write_u32::<BigEndian>(MISSING_VALUE_LENGTH).unwrap(),
+            }
+        }
+        Response::new(200, body).header("Content-Type", "application/octet-stream")
+    } else {
+        let entries: Vec<MgetEntry> = keys.iter()
+            .zip(values)
+            .map(|(key, value)| {
+                MgetEntry {
+                    key: base64::encode(key),
+                    found: value.is_some(),
+                    value: value.map(|value| base64::encode(&value)),
+                }
+            })
+            .collect();
+        Response::new(200, serde_json::to_vec(&entries).unwrap())
+            .header("Content-Type", "application/json")
+    }
+}
+
+fn respond_prefix<KV: KvStore>(kvstore: &KV,
+                               metrics: &Metrics,
+                               request: &Request,
+                               encoded_prefix: &str)
+                               -> Response {
+    let response = prefix_response(kvstore, request, encoded_prefix);
+    let outcome = match response.status {
+        200 => Outcome::Ok,
+        501 => Outcome::Unsupported,
+        _ => Outcome::Error,
+    };
+    metrics.record(PROTOCOL, "prefix", outcome);
+    response
+}
+
+fn prefix_response<KV: KvStore>(kvstore: &KV, request: &Request, encoded_prefix: &str) -> Response {
+    if request.method != "GET" && request.method != "HEAD" {
+        trace!("http:method not allowed {}", request.method);
+        return Response::new(405, "").header("Allow", "GET, HEAD");
+    }
+    let prefix = match percent_decode(encoded_prefix) {
+        Some(prefix) => prefix,
+        None => {
+            trace!("http:bad prefix encoding {}", encoded_prefix);
+            return Response::new(400, "bad percent-encoding in prefix");
+        }
+    };
+    let after = match request.query_param("after") {
+        Some(encoded) => {
+            match percent_decode(encoded).and_then(|after| base64::decode(&after).ok()) {
+                Some(after) => Some(after),
+                None => return Response::new(400, "bad base64 key in after"),
+            }
+        }
+        None => None,
+    };
+    let limit = match request.query_param("limit").map(|s| s.parse::<usize>()) {
+        Some(Ok(limit)) if limit > 0 && limit <= MAX_PREFIX_LIMIT => limit,
+        Some(_) => {
+            return Response::new(400, format!("limit must be from 1 to {}", MAX_PREFIX_LIMIT))
+        }
+        None => DEFAULT_PREFIX_LIMIT,
+    };
+    let accept = request.header("accept").unwrap_or("*/*");
+    if !accept.contains("application/json") && !accept.contains("*/*") {
+        trace!("http:prefix not acceptable {}", accept);
+        return Response::new(406, "supported type is application/json");
+    }
+    let mut response = None;
+    kvstore.with_sorted(&mut |sorted| {
+        response = Some(match sorted {
+            Some(sorted) => respond_prefix_page(sorted, &prefix, after.as_ref(), limit),
+            None => {
+                trace!("http:prefix on unsorted database");
+                Response::new(501, "prefix scans need a sorted database, such as MTBL")
+            }
+        })
+    });
+    response.expect("with_sorted didn't call back")
+}
+
+/// List up to `limit` pairs starting with `prefix`, continuing after the key `after` if given.
+/// The response gives the `after` for the next page, if there is one.
+fn respond_prefix_page(sorted: &SortedKvStore,
+                       prefix: &[u8],
+                       after: Option<&Vec<u8>>,
+                       limit: usize)
+                       -> Response {
+    trace!("http:prefix {:?} after {:?}", prefix, after);
+    let end = prefix_end(prefix);
+    let pairs = match after {
+        Some(after) if &after[..] >= prefix => {
+            let end = match end {
+                Some(ref end) => Bound::Excluded(&end[..]),
+                None => Bound::Unbounded,
+            };
+            sorted.range(Bound::Excluded(&after[..]), end)
+        }
+        _ => sorted.prefix(prefix),
+    };
+    // Look one past the page to see whether there's another.
+    let mut pairs: Vec<_> = pairs.take(limit + 1).collect();
+    let next = if pairs.len() > limit {
+        pairs.truncate(limit);
+        Some(base64::encode(&pairs[limit - 1].0))
+    } else {
+        None
+    };
+    let page = PrefixPage {
+        pairs: pairs.iter()
+            .map(|&(ref key, ref value)| {
+                PrefixEntry {
+                    key: base64::encode(key),
+                    value: base64::encode(value),
+                }
+            })
+            .collect(),
+        next: next,
+    };
+    Response::new(200, serde_json::to_vec(&page).unwrap())
+        .header("Content-Type", "application/json")
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::ops::Bound;
+    use std::sync::Arc;
+    use std::thread;
+
+    use kvstore::{KvStore, Pairs, SortedKvStore, Value};
+    use metrics::Metrics;
+    use metrics::connections::ConnectionMetrics;
+
+    /// The pairs in DummyKvStore, in key order
+    const PAIRS: &'static [(&'static [u8], &'static str)] = &[(b"a/b c", "xyz"),
+                                                               (b"bin\xff", "e"),
+                                                               (b"k", "v"),
+                                                               (b"user:1", "a"),
+                                                               (b"user:2", "b"),
+                                                               (b"user:3", "c"),
+                                                               (b"users", "d")];
+
+    /// A sorted KvStore with PAIRS
+    struct DummyKvStore {
+    }
+
+    impl KvStore for DummyKvStore {
+        fn get(&self, key: &[u8]) -> Option<Value> {
+            PAIRS.iter().find(|&&(k, _)| k == key).map(|&(_, v)| Value::from(v))
+        }
+
+        fn with_sorted(&self, f: &mut FnMut(Option<&SortedKvStore>)) {
+            f(Some(self))
+        }
+    }
+
+    impl SortedKvStore for DummyKvStore {
+        fn range<'a>(&'a self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Pairs<'a> {
+            let start = match start {
+                Bound::Included(k) => Bound::Included(k.to_vec()),
+                Bound::Excluded(k) => Bound::Excluded(k.to_vec()),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            let end = match end {
+                Bound::Included(k) => Bound::Included(k.to_vec()),
+                Bound::Excluded(k) => Bound::Excluded(k.to_vec()),
+                Bound::Unbounded => Bound::Unbounded,
+            };
+            Box::new(PAIRS.iter()
+                .map(|&(k, v)| (k.to_vec(), Value::from(v)))
+                .filter(move |&(ref k, _)| match start {
+                    Bound::Included(ref s) => k >= s,
+                    Bound::Excluded(ref s) => k > s,
+                    Bound::Unbounded => true,
+                })
+                .filter(move |&(ref k, _)| match end {
+                    Bound::Included(ref e) => k <= e,
+                    Bound::Excluded(ref e) => k < e,
+                    Bound::Unbounded => true,
+                }))
+        }
+    }
+
+    /// A KvStore that can't list its keys
+    struct UnsortedKvStore {
+    }
+
+    impl KvStore for UnsortedKvStore {
+        fn get(&self, _key: &[u8]) -> Option<Value> {
+            None
+        }
+    }
+
+    fn make_server_conn(metrics: Arc<Metrics>) -> TcpStream {
+        let listener = TcpListener::bind(("localhost", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client_conn = TcpStream::connect(("localhost", port)).unwrap();
+        thread::spawn(move || {
+            let (server_stream, _) = listener.accept().unwrap();
+            let connections = ConnectionMetrics::register("http", &metrics);
+            super::handle_client(DummyKvStore {}, &metrics, &connections, server_stream)
+                .unwrap_or(());
+        });
+        client_conn
+    }
+
+    fn request_with_metrics(req: &str, metrics: Arc<Metrics>) -> Vec<u8> {
+        let mut client_stream = make_server_conn(metrics);
+        client_stream.write(req.as_bytes()).unwrap();
+        client_stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        client_stream.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    fn request_bytes(req: &str) -> Vec<u8> {
+        request_with_metrics(req, Arc::new(Metrics::new()))
+    }
+
+    fn request(req: &str) -> String {
+        String::from_utf8(request_bytes(req)).unwrap()
+    }
+
+    #[test]
+    fn test_get() {
+        assert_eq!("HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/octet-stream\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 1\r\n\r\nv",
+                   request("GET /v1/keys/k HTTP/1.1\r\nConnection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_percent_encoded_key() {
+        assert_eq!("HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/octet-stream\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 3\r\n\r\nxyz",
+                   request("GET /v1/keys/a%2Fb%20c HTTP/1.0\r\n\r\n"));
+        assert_eq!("HTTP/1.1 400 Bad Request\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 27\r\n\r\nbad percent-encoding in key",
+                   request("GET /v1/keys/%zz HTTP/1.0\r\n\r\n"));
+        // A sign isn't a hex digit.
+        assert_eq!("HTTP/1.1 400 Bad Request\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 27\r\n\r\nbad percent-encoding in key",
+                   request("GET /v1/keys/%+1 HTTP/1.0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_head_and_keep_alive() {
+        // Pipelined requests on one connection are answered in order.
+        assert_eq!("HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/octet-stream\r\n\
+                    Connection: keep-alive\r\n\
+                    Content-Length: 3\r\n\r\n\
+                    HTTP/1.1 404 Not Found\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 0\r\n\r\n",
+                   request("HEAD /v1/keys/a%2Fb%20c HTTP/1.1\r\n\r\n\
+                            GET /v1/keys/_ HTTP/1.1\r\nConnection: close\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_mget_json() {
+        // "k", "a/b c", "_" and "\xff"
+        let body = r#"["aw==", "YS9iIGM=", "Xw==", "/w=="]"#;
+        assert_eq!(format!("HTTP/1.1 200 OK\r\n\
+                            Content-Type: application/json\r\n\
+                            Connection: close\r\n\
+                            Content-Length: 149\r\n\r\n\
+                            [{{\"key\":\"aw==\",\"found\":true,\"value\":\"dg==\"}},\
+                            {{\"key\":\"YS9iIGM=\",\"found\":true,\"value\":\"eHl6\"}},\
+                            {{\"key\":\"Xw==\",\"found\":false}},\
+                            {{\"key\":\"/w==\",\"found\":false}}]"),
+                   request(&format!("POST /v1/mget HTTP/1.0\r\n\
+                                     Content-Type: application/json\r\n\
+                                     Content-Length: {}\r\n\r\n{}",
+                                    body.len(),
+                                    body)));
+    }
+
+    #[test]
+    fn test_mget_binary() {
+        let body = "k\r\n_\n";
+        assert_eq!(&b"HTTP/1.1 200 OK\r\n\
+                     Content-Type: application/octet-stream\r\n\
+                     Connection: close\r\n\
+                     Content-Length: 19\r\n\r\n\
+                     \0\0\0\x01k\0\0\0\x01v\
+                     \0\0\0\x01_\xff\xff\xff\xff"[..],
+                   &request_bytes(&format!("POST /v1/mget HTTP/1.0\r\n\
+                                           Accept: application/octet-stream\r\n\
+                                           Content-Length: {}\r\n\r\n{}",
+                                          body.len(),
+                                          body))[..]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!("HTTP/1.1 405 Method Not Allowed\r\n\
+                    Allow: GET, HEAD\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 0\r\n\r\n",
+                   request("PUT /v1/keys/k HTTP/1.0\r\nContent-Length: 1\r\n\r\nx"));
+        assert_eq!("HTTP/1.1 404 Not Found\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 0\r\n\r\n",
+                   request("GET /elsewhere HTTP/1.0\r\n\r\n"));
+        assert_eq!("HTTP/1.1 400 Bad Request\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 21\r\n\r\nbad request line \"hi\"",
+                   request("hi\r\n\r\n"));
+        assert_eq!("HTTP/1.1 413 Payload Too Large\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 21\r\n\r\nrequest body too long",
+                   request("POST /v1/mget HTTP/1.0\r\nContent-Length: 1000000000\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        let req = "GET /v1/keys/k HTTP/1.1\r\n\r\n\
+                   GET /v1/prefix/user HTTP/1.1\r\n\r\n\
+                   GET /elsewhere HTTP/1.0\r\n\r\n";
+        request_with_metrics(req, metrics.clone());
+        let rendered = metrics.render();
+        for line in &["cdbd_requests_total{protocol=\"http\",command=\"get\",\
+                       outcome=\"hit\"} 1\n",
+                      "cdbd_requests_total{protocol=\"http\",command=\"prefix\",\
+                       outcome=\"ok\"} 1\n",
+                      "cdbd_requests_total{protocol=\"http\",command=\"unknown\",\
+                       outcome=\"error\"} 1\n",
+                      "cdbd_lookup_duration_seconds_count{protocol=\"http\"} 1\n",
+                      "cdbd_connections_total{protocol=\"http\"} 1\n",
+                      &format!("cdbd_read_bytes_total{{protocol=\"http\"}} {}\n", req.len())[..]] {
+            assert!(rendered.contains(line), "{:?} missing from {}", line, rendered);
+        }
+    }
+
+    #[test]
+    fn test_prefix() {
+        assert_eq!("HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/json\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 97\r\n\r\n\
+                    {\"pairs\":[{\"key\":\"dXNlcjox\",\"value\":\"YQ==\"},\
+                    {\"key\":\"dXNlcjoy\",\"value\":\"Yg==\"}],\"next\":\"dXNlcjoy\"}",
+                   request("GET /v1/prefix/user%3A?limit=2 HTTP/1.0\r\n\r\n"));
+        assert_eq!("HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/json\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 57\r\n\r\n\
+                    {\"pairs\":[{\"key\":\"dXNlcjoz\",\"value\":\"Yw==\"}],\"next\":null}",
+                   request("GET /v1/prefix/user%3A?limit=2&after=dXNlcjoy HTTP/1.0\r\n\r\n"));
+        // Keys that aren't UTF-8 round-trip through base64.
+        assert_eq!("HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/json\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 57\r\n\r\n\
+                    {\"pairs\":[{\"key\":\"Ymlu/w==\",\"value\":\"ZQ==\"}],\"next\":null}",
+                   request("GET /v1/prefix/bin%FF HTTP/1.0\r\n\r\n"));
+        assert_eq!("HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/json\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 24\r\n\r\n\
+                    {\"pairs\":[],\"next\":null}",
+                   request("GET /v1/prefix/bin?after=Ymlu%2Fw%3D%3D HTTP/1.0\r\n\r\n"));
+        assert_eq!("HTTP/1.1 200 OK\r\n\
+                    Content-Type: application/json\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 24\r\n\r\n\
+                    {\"pairs\":[],\"next\":null}",
+                   request("GET /v1/prefix/nope HTTP/1.0\r\n\r\n"));
+        assert_eq!("HTTP/1.1 400 Bad Request\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 28\r\n\r\nlimit must be from 1 to 1000",
+                   request("GET /v1/prefix/user?limit=0 HTTP/1.0\r\n\r\n"));
+    }
+
+    #[test]
+    fn test_prefix_unsorted() {
+        let mut ins = Cursor::new("GET /v1/prefix/k HTTP/1.0\r\n\r\n".as_bytes());
+        let mut outs = Vec::new();
+        super::serve(UnsortedKvStore {}, &Metrics::new(), &mut ins, &mut outs).unwrap();
+        assert_eq!("HTTP/1.1 501 Not Implemented\r\n\
+                    Connection: close\r\n\
+                    Content-Length: 49\r\n\r\n\
+                    prefix scans need a sorted database, such as MTBL",
+                   String::from_utf8(outs).unwrap());
+    }
+}
@@ -1,23 +1,447 @@
-use super::KvStore;
+use super::{KvStore, Value};
 
-use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io;
+use std::path::Path;
 use std::sync::Arc;
 
-use objpool::Pool;
-use tinycdb::Cdb;
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::Mmap;
 
-pub type CdbPool = Arc<Pool<Box<Cdb>>>;
+/// The number of hash tables in a CDB file, whose positions and lengths start the file
+const NUM_TABLES: usize = 256;
 
-pub fn new_cdb_pool(p: &Path, pool_size: usize) -> CdbPool {
-    let p = PathBuf::from(p);
-    let pool = Pool::with_capacity(pool_size, move || Cdb::open(&p).unwrap());
-    // Warm up the pool.
-    (0..pool_size).map(|_: usize| (*pool).get()).collect::<Vec<_>>();
-    pool
+/// The two layouts of CDB file, which differ only in the size of their positions and lengths
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CdbFormat {
+    /// The original format, with 32-bit positions, limiting files to 4 GiB
+    Cdb,
+    /// The cdb64 format, with 64-bit positions
+    Cdb64,
 }
 
-impl KvStore for CdbPool {
-    fn get(self: &CdbPool, key: &[u8]) -> Option<Vec<u8>> {
-        Pool::get(&*self).find(&key).map(Vec::from)
+impl CdbFormat {
+    fn word_size(&self) -> usize {
+        match self {
+            &CdbFormat::Cdb => 4,
+            &CdbFormat::Cdb64 => 8,
+        }
+    }
+
+    fn header_size(&self) -> usize {
+        NUM_TABLES * 2 * self.word_size()
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            &CdbFormat::Cdb => "cdb",
+            &CdbFormat::Cdb64 => "cdb64",
+        }
+    }
+
+    /// Read the little-endian word at `pos`, which must be within `data`.
+    fn read_word(&self, data: &[u8], pos: usize) -> io::Result<usize> {
+        let word_size = self.word_size();
+        let bytes = match data.get(pos..pos.saturating_add(word_size)) {
+            Some(bytes) => bytes,
+            None => return Err(corrupt("position out of bounds")),
+        };
+        let word = match self {
+            &CdbFormat::Cdb => LittleEndian::read_u32(bytes) as u64,
+            &CdbFormat::Cdb64 => LittleEndian::read_u64(bytes),
+        };
+        if word > usize::max_value() as u64 {
+            return Err(corrupt("position out of bounds"));
+        }
+        Ok(word as usize)
+    }
+
+    /// The position and number of slots of each hash table
+    fn tables(&self, data: &[u8]) -> io::Result<Vec<(usize, usize)>> {
+        if data.len() < self.header_size() {
+            return Err(corrupt("too short"));
+        }
+        let mut tables = Vec::with_capacity(NUM_TABLES);
+        for i in 0..NUM_TABLES {
+            let pos = try!(self.read_word(data, i * 2 * self.word_size()));
+            let len = try!(self.read_word(data, (i * 2 + 1) * self.word_size()));
+            tables.push((pos, len));
+        }
+        Ok(tables)
+    }
+
+    /// Check that `data` could be a file of this format: that its hash tables lie within the file
+    /// and after the header. Checking that also means lookups needn't.
+    fn check_header(&self, data: &[u8]) -> io::Result<()> {
+        for (pos, len) in try!(self.tables(data)) {
+            if len == 0 {
+                continue;
+            }
+            if pos < self.header_size() {
+                return Err(corrupt("hash table overlaps header"));
+            }
+            let end = len.checked_mul(2 * self.word_size()).and_then(|size| pos.checked_add(size));
+            match end {
+                Some(end) if end <= data.len() => {}
+                _ => return Err(corrupt("hash table out of bounds")),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `data` is laid out the way cdbmake lays out this format: the hash tables after
+    /// the records, one after another, ending the file. The format doesn't require that, but
+    /// it tells the formats apart when a file's header makes sense either way.
+    fn is_cdbmake_layout(&self, data: &[u8]) -> bool {
+        let tables = match self.tables(data) {
+            Ok(tables) => tables,
+            Err(_) => return false,
+        };
+        let mut end = tables[0].0;
+        if end < self.header_size() {
+            return false;
+        }
+        for (pos, len) in tables {
+            if pos != end {
+                return false;
+            }
+            end = pos.saturating_add(len.saturating_mul(2 * self.word_size()));
+        }
+        end == data.len()
+    }
+}
+
+/// A CDB file, memory-mapped once and shared by every thread.
+///
+/// Lookups read straight from the mapping without locking, and found values are returned as
+/// ranges of it. The file must not be modified in place while it's served; replace it by
+/// renaming a new file over it instead.
+pub struct CdbReader {
+    mmap: Arc<Mmap>,
+    format: CdbFormat,
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt CDB: {}", what))
+}
+
+/// The CDB hash function, from djb's original. cdb64 uses it too.
+fn hash(key: &[u8]) -> u32 {
+    key.iter().fold(5381u32, |h, &c| (h << 5).wrapping_add(h) ^ c as u32)
+}
+
+/// Open a classic CDB file.
+pub fn new_cdb(p: &Path) -> io::Result<CdbReader> {
+    open(p, CdbFormat::Cdb)
+}
+
+/// Open a cdb64 file.
+pub fn new_cdb64(p: &Path) -> io::Result<CdbReader> {
+    open(p, CdbFormat::Cdb64)
+}
+
+fn open(p: &Path, format: CdbFormat) -> io::Result<CdbReader> {
+    let error = |e: io::Error| {
+        io::Error::new(e.kind(),
+                       format!("error opening {} {}: {}", format.name(), p.display(), e))
+    };
+    let file = try!(File::open(p).map_err(&error));
+    // Empty files can't be mapped.
+    if try!(file.metadata().map_err(&error)).len() == 0 {
+        return Err(error(corrupt("too short")));
+    }
+    let mmap = try!(unsafe { Mmap::map(&file) }.map_err(&error));
+    // Say so if the file is fine, just in the other format. A cdb64 header can make sense read
+    // as classic CDB, so when both fit, go by which one cdbmake's layout matches.
+    let checked = format.check_header(&mmap);
+    let other = match format {
+        CdbFormat::Cdb => CdbFormat::Cdb64,
+        CdbFormat::Cdb64 => CdbFormat::Cdb,
+    };
+    let is_other = other.check_header(&mmap).is_ok() &&
+                   (checked.is_err() ||
+                    !format.is_cdbmake_layout(&mmap) && other.is_cdbmake_layout(&mmap));
+    if is_other {
+        return Err(error(io::Error::new(io::ErrorKind::InvalidData,
+                                        format!("it's a {} file, not {}; serve it with --{} (or \
+                                                 as {}:PATH)",
+                                                other.name(),
+                                                format.name(),
+                                                other.name(),
+                                                other.name()))));
+    }
+    try!(checked.map_err(&error));
+    Ok(CdbReader {
+        mmap: Arc::new(mmap),
+        format: format,
+    })
+}
+
+impl CdbReader {
+    /// Find where the value for `key` lies in the file.
+    fn find(&self, key: &[u8]) -> io::Result<Option<(usize, usize)>> {
+        let data = &self.mmap[..];
+        let format = self.format;
+        let word_size = format.word_size();
+        let h = hash(key);
+        let table = (h as usize % NUM_TABLES) * 2 * word_size;
+        let table_pos = try!(format.read_word(data, table));
+        let num_slots = try!(format.read_word(data, table + word_size));
+        if num_slots == 0 {
+            return Ok(None);
+        }
+        // Probe linearly from the key's slot until we hit an empty one.
+        let first_slot = (h as usize / NUM_TABLES) % num_slots;
+        for i in 0..num_slots {
+            let slot_pos = table_pos + ((first_slot + i) % num_slots) * 2 * word_size;
+            let record_pos = try!(format.read_word(data, slot_pos + word_size));
+            if record_pos == 0 {
+                return Ok(None);
+            }
+            if try!(format.read_word(data, slot_pos)) != h as usize {
+                continue;
+            }
+            let key_len = try!(format.read_word(data, record_pos));
+            let value_len = try!(format.read_word(data, record_pos.saturating_add(word_size)));
+            let key_start = record_pos.saturating_add(2 * word_size);
+            let value_start = key_start.saturating_add(key_len);
+            if value_start.saturating_add(value_len) > data.len() {
+                return Err(corrupt("record out of bounds"));
+            }
+            if &data[key_start..value_start] == key {
+                return Ok(Some((value_start, value_start + value_len)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl KvStore for CdbReader {
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        match self.find(key) {
+            Ok(Some((start, end))) => {
+                Some(Value::Mapped {
+                    mmap: self.mmap.clone(),
+                    start: start,
+                    end: end,
+                })
+            }
+            Ok(None) => None,
+            Err(e) => {
+                error!("{} lookup failed: {}", self.format.name(), e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+    use tinycdb::Cdb;
+
+    use kvstore::KvStore;
+    use super::{hash, new_cdb, new_cdb64, CdbFormat, NUM_TABLES};
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("cdbd-test-cdb-{}-{}.cdb", name, ::std::process::id()));
+        fs::remove_file(&path).unwrap_or(());
+        path
+    }
+
+    /// Write a file of either format the way cdbmake does, since there's no cdb64 tool to hand.
+    fn make_cdb(path: &PathBuf, format: CdbFormat, pairs: &[(&[u8], &[u8])]) {
+        let write_word = |out: &mut Vec<u8>, word: usize| match format {
+            CdbFormat::Cdb => out.write_u32::<LittleEndian>(word as u32).unwrap(),
+            CdbFormat::Cdb64 => out.write_u64::<LittleEndian>(word as u64).unwrap(),
+        };
+        let mut records = Vec::new();
+        let mut tables = vec![Vec::new(); NUM_TABLES];
+        for &(key, value) in pairs {
+            let h = hash(key);
+            tables[h as usize % NUM_TABLES].push((h, format.header_size() + records.len()));
+            write_word(&mut records, key.len());
+            write_word(&mut records, value.len());
+            records.extend_from_slice(key);
+            records.extend_from_slice(value);
+        }
+        let mut header = Vec::new();
+        let mut slots = Vec::new();
+        for table in tables {
+            let num_slots = table.len() * 2;
+            write_word(&mut header, format.header_size() + records.len() + slots.len());
+            write_word(&mut header, num_slots);
+            let mut table_slots = vec![(0, 0); num_slots];
+            for (h, pos) in table {
+                let mut slot = (h as usize / NUM_TABLES) % num_slots;
+                while table_slots[slot].1 != 0 {
+                    slot = (slot + 1) % num_slots;
+                }
+                table_slots[slot] = (h as usize, pos);
+            }
+            for (h, pos) in table_slots {
+                write_word(&mut slots, h);
+                write_word(&mut slots, pos);
+            }
+        }
+        let mut file = fs::File::create(path).unwrap();
+        file.write_all(&header).unwrap();
+        file.write_all(&records).unwrap();
+        file.write_all(&slots).unwrap();
+    }
+
+    #[test]
+    fn test_cdb() {
+        // Write the file with tinycdb's cdb_make, which writes the standard format.
+        let path = temp_path("cdb");
+        Cdb::new(&path, |creator| {
+                creator.add(b"k", b"v").unwrap();
+                creator.add(b"empty", b"").unwrap();
+                creator.add(b"\x00\xff", b"binary").unwrap();
+                // Enough keys to fill tables with collisions.
+                for i in 0..2000 {
+                    let key = format!("key{}", i);
+                    creator.add(key.as_bytes(), format!("value{}", i).as_bytes()).unwrap();
+                }
+            })
+            .unwrap();
+        let kvstore = new_cdb(&path).unwrap();
+        assert_eq!(b"v", &*kvstore.get(b"k").unwrap());
+        assert_eq!(b"", &*kvstore.get(b"empty").unwrap());
+        assert_eq!(b"binary", &*kvstore.get(b"\x00\xff").unwrap());
+        for i in 0..2000 {
+            let key = format!("key{}", i);
+            assert_eq!(format!("value{}", i).as_bytes(),
+                       &*kvstore.get(key.as_bytes()).unwrap());
+        }
+        assert!(kvstore.get(b"_").is_none());
+        assert!(kvstore.get(b"").is_none());
+        assert!(kvstore.get(b"key2000").is_none());
+        // Values stay readable after the file is removed.
+        let value = kvstore.get(b"k").unwrap();
+        drop(kvstore);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(b"v", &*value);
+    }
+
+    /// A file checked in under testdata/. The CDB files there are cdbmake's output for the .txt
+    /// file of the same name: `cdbmake NAME.cdb NAME.tmp < NAME.txt`.
+    fn testdata(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata").join(name)
+    }
+
+    #[test]
+    fn test_cdbmake() {
+        let kvstore = new_cdb(&testdata("cdbmake.cdb")).unwrap();
+        assert_eq!(b"v", &*kvstore.get(b"k").unwrap());
+        assert_eq!(b"", &*kvstore.get(b"empty").unwrap());
+        assert_eq!(b"empty key", &*kvstore.get(b"").unwrap());
+        assert_eq!(b"binary", &*kvstore.get(b"\x00\xff").unwrap());
+        assert_eq!(b"value\r\nwith newline", &*kvstore.get(b"line\nbreak").unwrap());
+        // When a key is given twice, the first value wins.
+        assert_eq!(b"first", &*kvstore.get(b"dup").unwrap());
+        for i in 0..300 {
+            let key = format!("key{}", i);
+            assert_eq!(format!("value{}", i).as_bytes(),
+                       &*kvstore.get(key.as_bytes()).unwrap());
+        }
+        assert!(kvstore.get(b"_").is_none());
+        assert!(kvstore.get(b"key300").is_none());
+    }
+
+    #[test]
+    fn test_cdbmake_empty() {
+        let kvstore = new_cdb(&testdata("empty.cdb")).unwrap();
+        assert!(kvstore.get(b"k").is_none());
+        assert!(kvstore.get(b"").is_none());
+    }
+
+    #[test]
+    fn test_other_layout() {
+        // Rewrite cdbmake's file with its hash tables in reverse order, empty ones at position 0,
+        // and padding after them, which the format allows.
+        let data = fs::read(testdata("cdbmake.cdb")).unwrap();
+        let word = |pos: usize| LittleEndian::read_u32(&data[pos..pos + 4]) as usize;
+        let records_end = word(0);
+        let mut header = vec![0; 2048];
+        let mut tables = Vec::new();
+        for i in (0..NUM_TABLES).rev() {
+            let (pos, len) = (word(i * 8), word(i * 8 + 4));
+            if len > 0 {
+                LittleEndian::write_u32(&mut header[i * 8..],
+                                        (records_end + tables.len()) as u32);
+                LittleEndian::write_u32(&mut header[i * 8 + 4..], len as u32);
+                tables.extend_from_slice(&data[pos..pos + len * 8]);
+            }
+        }
+        let path = temp_path("other-layout");
+        let mut file = fs::File::create(&path).unwrap();
+        file.write_all(&header).unwrap();
+        file.write_all(&data[2048..records_end]).unwrap();
+        file.write_all(&tables).unwrap();
+        file.write_all(&[0; 100]).unwrap();
+        drop(file);
+        let kvstore = new_cdb(&path).unwrap();
+        assert_eq!(b"v", &*kvstore.get(b"k").unwrap());
+        assert_eq!(b"first", &*kvstore.get(b"dup").unwrap());
+        for i in 0..300 {
+            let key = format!("key{}", i);
+            assert_eq!(format!("value{}", i).as_bytes(),
+                       &*kvstore.get(key.as_bytes()).unwrap());
+        }
+        assert!(kvstore.get(b"_").is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_not_cdb() {
+        let path = temp_path("not-cdb");
+        fs::File::create(&path).unwrap().write_all(b"not a CDB").unwrap();
+        assert!(new_cdb(&path).is_err());
+        // A header whose first hash table runs past the end of the file
+        let mut header = vec![0; 2048];
+        header[0..8].copy_from_slice(&[0x00, 0x08, 0, 0, 0x10, 0, 0, 0]);
+        fs::File::create(&path).unwrap().write_all(&header).unwrap();
+        assert!(new_cdb(&path).is_err());
+        fs::remove_file(&path).unwrap();
+        assert!(new_cdb(&path).is_err());
+    }
+
+    #[test]
+    fn test_cdb64() {
+        let path = temp_path("cdb64");
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = (0..2000)
+            .map(|i| (format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes()))
+            .collect();
+        let pairs: Vec<(&[u8], &[u8])> = pairs.iter().map(|&(ref k, ref v)| (&k[..], &v[..])).collect();
+        make_cdb(&path, CdbFormat::Cdb64, &pairs);
+        let kvstore = new_cdb64(&path).unwrap();
+        for &(key, value) in &pairs {
+            assert_eq!(value, &*kvstore.get(key).unwrap());
+        }
+        assert!(kvstore.get(b"_").is_none());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_wrong_format() {
+        let path = temp_path("wrong-format");
+        make_cdb(&path, CdbFormat::Cdb64, &[(b"k", b"v")]);
+        let message = new_cdb(&path).err().unwrap().to_string();
+        assert!(message.contains("it's a cdb64 file"), "{}", message);
+        make_cdb(&path, CdbFormat::Cdb, &[(b"k", b"v")]);
+        assert_eq!(b"v", &*new_cdb(&path).unwrap().get(b"k").unwrap());
+        let message = new_cdb64(&path).err().unwrap().to_string();
+        assert!(message.contains("it's a cdb file"), "{}", message);
+        // Empty databases are recognized too.
+        make_cdb(&path, CdbFormat::Cdb64, &[]);
+        assert!(new_cdb64(&path).unwrap().get(b"k").is_none());
+        assert!(new_cdb(&path).is_err());
+        fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,146 @@
+use super::{KvStore, Value};
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use lmdb::{self, Database, Environment, Transaction};
+
+/// How many threads may read at once; LMDB's default of 126 is too few for thread-per-connection
+/// servers.
+const MAX_READERS: u32 = 4096;
+
+/// How many named databases may be opened in one environment
+const MAX_DBS: u32 = 64;
+
+/// The environments open in this process, by path. LMDB mustn't open the same environment twice
+/// in one process: closing either handle releases the process's locks on it. So reloading an
+/// LMDB database reuses its open environment, which already sees each commit as it's made.
+static ENVIRONMENTS: Mutex<Vec<(PathBuf, Weak<Environment>)>> = Mutex::new(Vec::new());
+
+/// A read-only LMDB environment, serving one of its databases
+pub struct LmdbStore {
+    env: Arc<Environment>,
+    db: Database,
+}
+
+fn lmdb_error(p: &Path, e: lmdb::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other,
+                   format!("error opening LMDB {}: {}", p.display(), e))
+}
+
+/// Open the LMDB environment at `p`, which may be a directory or a single file, or share it if
+/// it's already open. If `subdb` is given, serve that named database instead of the main one.
+pub fn new_lmdb(p: &Path, subdb: Option<&str>) -> io::Result<LmdbStore> {
+    let path = try!(p.canonicalize().map_err(|e| {
+        io::Error::new(e.kind(), format!("error opening LMDB {}: {}", p.display(), e))
+    }));
+    let env = try!(open_env(&path));
+    let db = try!(env.open_db(subdb).map_err(|e| lmdb_error(p, e)));
+    Ok(LmdbStore { env: env, db: db })
+}
+
+/// The environment at the canonical `path`, opening it unless it's already open.
+fn open_env(path: &Path) -> io::Result<Arc<Environment>> {
+    let mut environments = ENVIRONMENTS.lock().unwrap();
+    environments.retain(|&(_, ref env)| env.upgrade().is_some());
+    if let Some(env) = environments.iter()
+        .find(|&&(ref p, _)| p == path)
+        .and_then(|&(_, ref env)| env.upgrade()) {
+        return Ok(env);
+    }
+    let mut flags = lmdb::EnvironmentFlags::READ_ONLY;
+    if path.is_file() {
+        flags = flags | lmdb::EnvironmentFlags::NO_SUB_DIR;
+    }
+    let env = Arc::new(try!(Environment::new()
+        .set_flags(flags)
+        .set_max_readers(MAX_READERS)
+        .set_max_dbs(MAX_DBS)
+        .open(path)
+        .map_err(|e| lmdb_error(path, e))));
+    environments.push((path.to_path_buf(), Arc::downgrade(&env)));
+    Ok(env)
+}
+
+impl KvStore for LmdbStore {
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        // Each lookup gets its own read transaction. LMDB ties read transactions to the calling
+        // thread, and reuses that thread's reader slot from one transaction to the next.
+        let txn = match self.env.begin_ro_txn() {
+            Ok(txn) => txn,
+            Err(e) => {
+                error!("failed to begin LMDB read transaction: {}", e);
+                return None;
+            }
+        };
+        match txn.get(self.db, &key) {
+            Ok(value) => Some(Value::from(value)),
+            Err(lmdb::Error::NotFound) => None,
+            // Keys too long for LMDB can't be present.
+            Err(lmdb::Error::BadValSize) => None,
+            Err(e) => {
+                error!("LMDB lookup failed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use lmdb::{self, Environment, Transaction};
+
+    use kvstore::{KvStore, Value};
+    use super::new_lmdb;
+
+    fn make_env(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cdbd-test-lmdb-{}-{}", name, ::std::process::id()));
+        fs::remove_dir_all(&dir).unwrap_or(());
+        fs::create_dir_all(&dir).unwrap();
+        let env = Environment::new().set_max_dbs(1).open(&dir).unwrap();
+        let main_db = env.open_db(None).unwrap();
+        let sub_db = env.create_db(Some("sub"), lmdb::DatabaseFlags::empty()).unwrap();
+        let mut txn = env.begin_rw_txn().unwrap();
+        txn.put(main_db, &"k", &"v", lmdb::WriteFlags::empty()).unwrap();
+        txn.put(sub_db, &"k", &"sub v", lmdb::WriteFlags::empty()).unwrap();
+        txn.commit().unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_lmdb() {
+        let dir = make_env("main");
+        let kvstore = new_lmdb(&dir, None).unwrap();
+        assert_eq!(Some(Value::from("v")), kvstore.get(b"k"));
+        assert_eq!(None, kvstore.get(b"_"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lmdb_subdb() {
+        let dir = make_env("subdb");
+        let kvstore = new_lmdb(&dir, Some("sub")).unwrap();
+        assert_eq!(Some(Value::from("sub v")), kvstore.get(b"k"));
+        assert!(new_lmdb(&dir, Some("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_lmdb_shares_env() {
+        let dir = make_env("shared");
+        let first = new_lmdb(&dir, None).unwrap();
+        // Opening it again, as a reload does, shares the open environment.
+        let second = new_lmdb(&dir, Some("sub")).unwrap();
+        assert!(Arc::ptr_eq(&first.env, &second.env));
+        assert_eq!(Some(Value::from("sub v")), second.get(b"k"));
+        drop(first);
+        assert_eq!(Some(Value::from("sub v")), second.get(b"k"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
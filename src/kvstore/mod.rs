@@ -1,20 +1,142 @@
+use std::fmt;
+use std::ops::{Bound, Deref};
 use std::sync::Arc;
 
+use memmap::Mmap;
+
+/// A value found in a KvStore.
+///
+/// Values in memory-mapped files are returned as a range of the mapping rather than copied out,
+/// and keep the mapping alive for as long as they're held, even across a reload.
+pub enum Value {
+    Owned(Vec<u8>),
+    Mapped {
+        mmap: Arc<Mmap>,
+        start: usize,
+        end: usize,
+    },
+}
+
+impl Deref for Value {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            &Value::Owned(ref v) => v,
+            &Value::Mapped { ref mmap, start, end } => &mmap[start..end],
+        }
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(v: Vec<u8>) -> Value {
+        Value::Owned(v)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::Owned(s.into_bytes())
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::Owned(s.as_bytes().to_vec())
+    }
+}
+
+impl<'a> From<&'a [u8]> for Value {
+    fn from(v: &'a [u8]) -> Value {
+        Value::Owned(v.to_vec())
+    }
+}
+
+impl AsRef<[u8]> for Value {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        **self == **other
+    }
+}
+
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
 pub trait KvStore {
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+    fn get(&self, key: &[u8]) -> Option<Value>;
+
+    /// Call `f` with this store as a SortedKvStore, or with None if it can't list its keys in
+    /// order.
+    ///
+    /// This takes a callback instead of returning the store so that wrappers, like
+    /// ReloadableKvStore, can hold onto one database for the whole of a scan.
+    fn with_sorted(&self, f: &mut FnMut(Option<&SortedKvStore>)) {
+        f(None)
+    }
 }
 
 impl KvStore for Arc<KvStore + Send + Sync> {
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    fn get(&self, key: &[u8]) -> Option<Value> {
         (**self).get(key)
     }
+
+    fn with_sorted(&self, f: &mut FnMut(Option<&SortedKvStore>)) {
+        (**self).with_sorted(f)
+    }
 }
 
 impl KvStore for Box<KvStore> {
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+    fn get(&self, key: &[u8]) -> Option<Value> {
         (**self).get(key)
     }
+
+    fn with_sorted(&self, f: &mut FnMut(Option<&SortedKvStore>)) {
+        (**self).with_sorted(f)
+    }
+}
+
+/// Key/value pairs in key order
+pub type Pairs<'a> = Box<Iterator<Item = (Vec<u8>, Value)> + 'a>;
+
+/// A KvStore whose keys are sorted, so that it can list them by prefix or range
+pub trait SortedKvStore: KvStore {
+    /// Iterate over the pairs whose keys lie between `start` and `end`.
+    fn range<'a>(&'a self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Pairs<'a>;
+
+    /// Iterate over the pairs whose keys start with `prefix`.
+    fn prefix<'a>(&'a self, prefix: &[u8]) -> Pairs<'a> {
+        match prefix_end(prefix) {
+            Some(end) => self.range(Bound::Included(prefix), Bound::Excluded(&end)),
+            None => self.range(Bound::Included(prefix), Bound::Unbounded),
+        }
+    }
+}
+
+/// The first key after every key starting with `prefix`, or None if there's no such key (when
+/// the prefix is empty or all 0xff bytes).
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Some(end);
+        }
+    }
+    None
 }
 
 pub mod cdb;
+pub mod lmdb;
 pub mod mtbl;
+pub mod overlay;
+pub mod reload;
+pub mod routing;
+pub mod sqlite;
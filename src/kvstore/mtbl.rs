@@ -1,15 +1,55 @@
-use super::KvStore;
+use super::{KvStore, Pairs, SortedKvStore, Value};
 
+use std::io;
+use std::ops::Bound;
 use std::path::Path;
 
 use mtbl::{Read, Reader};
 
 impl KvStore for Reader {
-    fn get(self: &Self, key: &[u8]) -> Option<Vec<u8>> {
-        Read::get(self, key)
+    fn get(self: &Self, key: &[u8]) -> Option<Value> {
+        Read::get(self, key).map(Value::from)
+    }
+
+    fn with_sorted(&self, f: &mut FnMut(Option<&SortedKvStore>)) {
+        f(Some(self))
+    }
+}
+
+impl SortedKvStore for Reader {
+    fn range<'a>(&'a self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Pairs<'a> {
+        let start_key = match start {
+            Bound::Included(key) | Bound::Excluded(key) => key.to_vec(),
+            Bound::Unbounded => Vec::new(),
+        };
+        let iter: Box<Iterator<Item = (Vec<u8>, Vec<u8>)> + 'a> = match end {
+            Bound::Included(end_key) | Bound::Excluded(end_key) => {
+                Box::new(self.get_range(&start_key, end_key))
+            }
+            // MTBL can only seek to a range with an end, so end the range at the key of all 0xff
+            // bytes one longer than the start, which sorts after it. Every key after that one
+            // starts with it, so seek to those by prefix.
+            Bound::Unbounded => {
+                let last_key = vec![0xff; start_key.len() + 1];
+                let through_last = self.get_range(&start_key, &last_key);
+                let after_last = self.get_prefix(&last_key)
+                    .skip_while(move |&(ref k, _)| *k == last_key);
+                Box::new(through_last.chain(after_last))
+            }
+        };
+        let mut pairs: Pairs<'a> = Box::new(iter.map(|(k, v)| (k, Value::from(v))));
+        if let Bound::Excluded(_) = start {
+            pairs = Box::new(pairs.skip_while(move |&(ref k, _)| *k == start_key));
+        }
+        if let Bound::Excluded(end_key) = end {
+            let end_key = end_key.to_vec();
+            pairs = Box::new(pairs.take_while(move |&(ref k, _)| *k < end_key));
+        }
+        pairs
     }
 }
 
-pub fn new_mtbl(p: &Path) -> Reader {
-    Reader::open_from_path(p).unwrap()
+pub fn new_mtbl(p: &Path) -> io::Result<Reader> {
+    Reader::open_from_path(p)
+        .map_err(|e| io::Error::new(e.kind(), format!("error opening mtbl {}: {}", p.display(), e)))
 }
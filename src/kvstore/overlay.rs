@@ -0,0 +1,70 @@
+use super::{KvStore, Value};
+
+use std::sync::Arc;
+
+/// A KvStore that consults a stack of databases in order, returning the first hit.
+///
+/// This lets small delta files be layered over a large base file. If a tombstone value is set, a
+/// layer can delete a key from the layers below it by storing the tombstone as its value.
+pub struct OverlayKvStore {
+    layers: Vec<Arc<KvStore + Send + Sync>>,
+    tombstone: Option<Vec<u8>>,
+}
+
+impl OverlayKvStore {
+    pub fn new(layers: Vec<Arc<KvStore + Send + Sync>>,
+               tombstone: Option<Vec<u8>>)
+               -> OverlayKvStore {
+        OverlayKvStore {
+            layers: layers,
+            tombstone: tombstone,
+        }
+    }
+}
+
+impl KvStore for OverlayKvStore {
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        self.layers
+            .iter()
+            .filter_map(|layer| layer.get(key))
+            .next()
+            .and_then(|value| if self.tombstone.as_ref().map(|t| &t[..]) == Some(&value[..]) {
+                None
+            } else {
+                Some(value)
+            })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use kvstore::{KvStore, Value};
+    use super::OverlayKvStore;
+
+    /// A KvStore with the given pairs
+    struct DummyKvStore {
+        pairs: Vec<(&'static str, &'static str)>,
+    }
+
+    impl KvStore for DummyKvStore {
+        fn get(&self, key: &[u8]) -> Option<Value> {
+            self.pairs
+                .iter()
+                .find(|&&(k, _)| k.as_bytes() == key)
+                .map(|&(_, v)| Value::from(v.as_bytes()))
+        }
+    }
+
+    #[test]
+    fn test_overlay() {
+        let delta = Arc::new(DummyKvStore { pairs: vec![("a", "new"), ("b", "DELETED")] });
+        let base = Arc::new(DummyKvStore { pairs: vec![("a", "old"), ("b", "old"), ("c", "old")] });
+        let kvstore = OverlayKvStore::new(vec![delta, base], Some("DELETED".as_bytes().to_vec()));
+        assert_eq!(Some(Value::from("new")), kvstore.get(b"a"));
+        assert_eq!(None, kvstore.get(b"b"));
+        assert_eq!(Some(Value::from("old")), kvstore.get(b"c"));
+        assert_eq!(None, kvstore.get(b"d"));
+    }
+}
@@ -0,0 +1,212 @@
+use super::{KvStore, SortedKvStore, Value};
+
+use std::io;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, TryLockError};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::SystemTime;
+
+#[cfg(target_os = "linux")]
+use inotify::{Inotify, WatchMask};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+
+type Opener = Box<Fn() -> io::Result<Arc<KvStore + Send + Sync>> + Send + Sync>;
+
+/// A KvStore whose database can be atomically replaced by reopening it.
+///
+/// Each lookup runs against whichever generation was current when it started, so lookups in
+/// flight during a reload finish against the old database, which is closed once the last of
+/// them is done.
+pub struct ReloadableKvStore {
+    current: RwLock<Arc<KvStore + Send + Sync>>,
+    /// When the current generation was opened
+    loaded: RwLock<SystemTime>,
+    /// How many times the database has been reloaded
+    generation: AtomicUsize,
+    open: Opener,
+    /// Held while reloading, so that concurrent reloads don't race to install their generation
+    reloading: Mutex<()>,
+}
+
+impl ReloadableKvStore {
+    pub fn new<F>(open: F) -> io::Result<ReloadableKvStore>
+        where F: Fn() -> io::Result<Arc<KvStore + Send + Sync>> + Send + Sync + 'static
+    {
+        let current = try!(open());
+        Ok(ReloadableKvStore {
+            current: RwLock::new(current),
+            loaded: RwLock::new(SystemTime::now()),
+            generation: AtomicUsize::new(0),
+            open: Box::new(open),
+            reloading: Mutex::new(()),
+        })
+    }
+
+    /// Reopen the database. If that fails, the current database stays in place.
+    ///
+    /// The new database is opened while the old one is still open, so stores that can't be open
+    /// twice at once, like LMDB's, must share what they already have open.
+    pub fn reload(&self) -> io::Result<()> {
+        let _reloading = self.reloading.lock().unwrap();
+        let kvstore = try!((self.open)());
+        let old = mem::replace(&mut *self.current.write().unwrap(), kvstore);
+        *self.loaded.write().unwrap() = SystemTime::now();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        // Release our hold on the old generation outside the lock.
+        drop(old);
+        Ok(())
+    }
+
+    fn reload_and_log(&self, reason: &str) {
+        info!("reloading database: {}", reason);
+        match self.reload() {
+            Ok(()) => info!("reloaded database"),
+            Err(e) => error!("failed to reload database, keeping the old one: {}", e),
+        }
+    }
+
+    fn current(&self) -> Arc<KvStore + Send + Sync> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Whether a reload is in progress
+    pub fn is_reloading(&self) -> bool {
+        match self.reloading.try_lock() {
+            Err(TryLockError::WouldBlock) => true,
+            _ => false,
+        }
+    }
+
+    /// Which generation of the database is being served, counting from 0 for the first one opened
+    pub fn generation(&self) -> usize {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// When the database being served was opened
+    pub fn loaded(&self) -> SystemTime {
+        *self.loaded.read().unwrap()
+    }
+}
+
+impl KvStore for ReloadableKvStore {
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        self.current().get(key)
+    }
+
+    fn with_sorted(&self, f: &mut FnMut(Option<&SortedKvStore>)) {
+        self.current().with_sorted(f)
+    }
+}
+
+/// Reload the database whenever we receive SIGHUP.
+pub fn reload_on_sighup(kvstore: Arc<ReloadableKvStore>) -> io::Result<()> {
+    let mut signals = try!(Signals::new(&[SIGHUP]));
+    thread::spawn(move || for _ in signals.forever() {
+        kvstore.reload_and_log("received SIGHUP");
+    });
+    Ok(())
+}
+
+/// Reload the database whenever one of its files is replaced by renaming another file over it.
+#[cfg(target_os = "linux")]
+pub fn reload_on_replace(kvstore: Arc<ReloadableKvStore>, paths: &[PathBuf]) -> io::Result<()> {
+    let mut inotify = try!(Inotify::init());
+    let mut watched = Vec::new();
+    for path in paths {
+        // Watch the directory, since a rename replaces the file (and its inode) that we'd be
+        // watching otherwise.
+        let dir = match path.parent() {
+            Some(dir) if dir.as_os_str().is_empty() => PathBuf::from("."),
+            Some(dir) => dir.to_path_buf(),
+            None => PathBuf::from("/"),
+        };
+        let name = match path.file_name() {
+            Some(name) => name.to_os_string(),
+            None => continue,
+        };
+        let wd = try!(inotify.add_watch(&dir, WatchMask::MOVED_TO));
+        watched.push((wd, name));
+    }
+    thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("stopped watching database files: {}", e);
+                    return;
+                }
+            };
+            let replaced: Vec<String> = events.filter(|event| {
+                    watched.iter().any(|&(ref wd, ref name)| {
+                        *wd == event.wd && event.name == Some(name.as_os_str())
+                    })
+                })
+                .map(|event| event.name.unwrap().to_string_lossy().into_owned())
+                .collect();
+            if !replaced.is_empty() {
+                kvstore.reload_and_log(&format!("replaced {}", replaced.join(", ")));
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn reload_on_replace(_kvstore: Arc<ReloadableKvStore>, _paths: &[PathBuf]) -> io::Result<()> {
+    warn!("watching for replaced database files is only supported on Linux; use SIGHUP");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use kvstore::{KvStore, Value};
+    use super::ReloadableKvStore;
+
+    /// A KvStore with one pair, {"k": generation}
+    struct GenerationKvStore {
+        generation: usize,
+    }
+
+    impl KvStore for GenerationKvStore {
+        fn get(&self, key: &[u8]) -> Option<Value> {
+            if key == "k".as_bytes() {
+                Some(Value::from(self.generation.to_string().into_bytes()))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_reload() {
+        // Every other open fails.
+        let opens = AtomicUsize::new(0);
+        let kvstore = ReloadableKvStore::new(move || {
+                let generation = opens.fetch_add(1, Ordering::SeqCst);
+                if generation % 2 == 0 {
+                    Ok(Arc::new(GenerationKvStore { generation: generation }) as
+                       Arc<KvStore + Send + Sync>)
+                } else {
+                    Err(io::Error::new(io::ErrorKind::Other, "corrupt"))
+                }
+            })
+            .unwrap();
+        let old = kvstore.current();
+        assert_eq!(Some(Value::from("0")), kvstore.get(b"k"));
+        // A failed open leaves the working database in place.
+        assert!(kvstore.reload().is_err());
+        assert_eq!(Some(Value::from("0")), kvstore.get(b"k"));
+        assert!(kvstore.reload().is_ok());
+        assert_eq!(Some(Value::from("2")), kvstore.get(b"k"));
+        // Lookups that started before the reload still see the old generation.
+        assert_eq!(Some(Value::from("0")), old.get(b"k"));
+    }
+}
@@ -0,0 +1,72 @@
+use super::{KvStore, Value};
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The byte separating a namespace from the rest of a key, as in "NAME:key"
+pub const NAMESPACE_SEPARATOR: u8 = b':';
+
+/// A KvStore that serves several databases, routing each lookup by its key's namespace prefix.
+///
+/// A lookup of "NAME:key" looks up "key" in the database named NAME.
+pub struct RoutingKvStore {
+    namespaces: HashMap<Vec<u8>, Arc<KvStore + Send + Sync>>,
+}
+
+impl RoutingKvStore {
+    pub fn new(namespaces: Vec<(String, Arc<KvStore + Send + Sync>)>) -> RoutingKvStore {
+        RoutingKvStore {
+            namespaces: namespaces.into_iter()
+                .map(|(name, kvstore)| (name.into_bytes(), kvstore))
+                .collect(),
+        }
+    }
+}
+
+impl KvStore for RoutingKvStore {
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        let separator = match key.iter().position(|&c| c == NAMESPACE_SEPARATOR) {
+            Some(i) => i,
+            None => return None,
+        };
+        self.namespaces
+            .get(&key[..separator])
+            .and_then(|kvstore| kvstore.get(&key[separator + 1..]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use kvstore::{KvStore, Value};
+    use super::RoutingKvStore;
+
+    /// A KvStore with one pair, {"k": value}
+    struct DummyKvStore {
+        value: &'static str,
+    }
+
+    impl KvStore for DummyKvStore {
+        fn get(&self, key: &[u8]) -> Option<Value> {
+            if key == "k".as_bytes() {
+                Some(Value::from(self.value.as_bytes()))
+            } else {
+                None
+            }
+        }
+    }
+
+    #[test]
+    fn test_routing() {
+        let kvstore = RoutingKvStore::new(vec![("a".to_string(),
+                                                Arc::new(DummyKvStore { value: "1" })),
+                                               ("b".to_string(),
+                                                Arc::new(DummyKvStore { value: "2" }))]);
+        assert_eq!(Some(Value::from("1")), kvstore.get(b"a:k"));
+        assert_eq!(Some(Value::from("2")), kvstore.get(b"b:k"));
+        assert_eq!(None, kvstore.get(b"a:_"));
+        assert_eq!(None, kvstore.get(b"c:k"));
+        assert_eq!(None, kvstore.get(b"k"));
+    }
+}
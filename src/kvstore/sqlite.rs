@@ -0,0 +1,191 @@
+use super::{KvStore, Value};
+
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use objpool::Pool;
+use rusqlite::{self, Connection, OpenFlags};
+use rusqlite::types::{ToSqlOutput, ValueRef};
+
+/// How to look up a key in a SQLite database
+#[derive(Debug, Clone)]
+pub enum SqliteQuery {
+    /// Look up the key column of a table, and return its value column
+    Table {
+        table: String,
+        key_column: String,
+        value_column: String,
+    },
+    /// Run a SELECT statement with the key as its one parameter, and return the first column of
+    /// the first row
+    Select(String),
+}
+
+impl SqliteQuery {
+    fn sql(&self) -> String {
+        match self {
+            &SqliteQuery::Table { ref table, ref key_column, ref value_column } => {
+                format!("SELECT {} FROM {} WHERE {} = ?1 LIMIT 1",
+                        quote_identifier(value_column),
+                        quote_identifier(table),
+                        quote_identifier(key_column))
+            }
+            &SqliteQuery::Select(ref sql) => sql.clone(),
+        }
+    }
+}
+
+fn quote_identifier(s: &str) -> String {
+    format!("\"{}\"", s.replace("\"", "\"\""))
+}
+
+/// A pool of read-only connections to a SQLite database, and the query to look keys up with
+pub struct SqlitePool {
+    pool: Arc<Pool<Connection>>,
+    sql: String,
+}
+
+fn sqlite_error(p: &Path, e: rusqlite::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other,
+                   format!("error opening SQLite {}: {}", p.display(), e))
+}
+
+/// Open a read-only connection. The file is opened as immutable, so SQLite won't take locks or
+/// look for a journal, and can never write to it.
+fn open_connection(p: &Path) -> rusqlite::Result<Connection> {
+    let mut uri = String::from("file:");
+    for c in p.to_string_lossy().chars() {
+        match c {
+            '?' | '#' | '%' => uri.push_str(&format!("%{:02X}", c as u32)),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?immutable=1");
+    Connection::open_with_flags(uri,
+                                OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_URI |
+                                OpenFlags::SQLITE_OPEN_NO_MUTEX)
+}
+
+pub fn new_sqlite_pool(p: &Path, query: &SqliteQuery, pool_size: usize) -> io::Result<SqlitePool> {
+    let sql = query.sql();
+    // Open (and check the query on) every connection up front, so that a bad file or query is
+    // reported here instead of panicking inside the pool.
+    let mut connections = Vec::with_capacity(pool_size);
+    for _ in 0..pool_size {
+        let connection = try!(open_connection(p).map_err(|e| sqlite_error(p, e)));
+        let parameters = try!(connection.prepare_cached(&sql).map_err(|e| sqlite_error(p, e)))
+            .parameter_count();
+        // The key is the only parameter we pass.
+        if parameters != 1 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("error opening SQLite {}: query {:?} takes {} \
+                                               parameters, but must take just the key",
+                                              p.display(),
+                                              sql,
+                                              parameters)));
+        }
+        connections.push(connection);
+    }
+    let connections = Mutex::new(connections);
+    let pool = Pool::with_capacity(pool_size, move || {
+        connections.lock().unwrap().pop().expect("SQLite pool exhausted")
+    });
+    // Warm up the pool.
+    let _warm: Vec<_> = (0..pool_size).map(|_: usize| (*pool).get()).collect();
+    Ok(SqlitePool {
+        pool: pool,
+        sql: sql,
+    })
+}
+
+impl SqlitePool {
+    fn lookup(&self, key: &[u8]) -> rusqlite::Result<Option<Vec<u8>>> {
+        let connection = self.pool.get();
+        let mut statement = try!(connection.prepare_cached(&self.sql));
+        // SQLite never considers TEXT equal to a BLOB, so pass keys as TEXT where we can, since
+        // that's how most tables store them.
+        let param = match ::std::str::from_utf8(key) {
+            Ok(s) => ToSqlOutput::Borrowed(ValueRef::Text(s.as_bytes())),
+            Err(_) => ToSqlOutput::Borrowed(ValueRef::Blob(key)),
+        };
+        let mut rows = try!(statement.query([param]));
+        let row = match try!(rows.next()) {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        Ok(match try!(row.get_ref(0)) {
+            ValueRef::Null => None,
+            ValueRef::Integer(i) => Some(i.to_string().into_bytes()),
+            ValueRef::Real(f) => Some(f.to_string().into_bytes()),
+            ValueRef::Text(s) => Some(s.to_vec()),
+            ValueRef::Blob(b) => Some(b.to_vec()),
+        })
+    }
+}
+
+impl KvStore for SqlitePool {
+    fn get(&self, key: &[u8]) -> Option<Value> {
+        match self.lookup(key) {
+            Ok(value) => value.map(Value::from),
+            Err(e) => {
+                error!("SQLite lookup failed: {}", e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use rusqlite::Connection;
+
+    use kvstore::{KvStore, Value};
+    use super::{new_sqlite_pool, SqliteQuery};
+
+    fn make_db(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("cdbd-test-sqlite-{}-{}.db", name, ::std::process::id()));
+        fs::remove_file(&path).unwrap_or(());
+        let connection = Connection::open(&path).unwrap();
+        connection.execute_batch("CREATE TABLE t (k TEXT PRIMARY KEY, v BLOB, n INTEGER);
+                                  INSERT INTO t VALUES ('k', x'76', 7);")
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_table() {
+        let path = make_db("table");
+        let query = SqliteQuery::Table {
+            table: "t".to_string(),
+            key_column: "k".to_string(),
+            value_column: "v".to_string(),
+        };
+        let kvstore = new_sqlite_pool(&path, &query, 2).unwrap();
+        assert_eq!(Some(Value::from("v")), kvstore.get(b"k"));
+        assert_eq!(None, kvstore.get(b"_"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_select() {
+        let path = make_db("select");
+        let query = SqliteQuery::Select("SELECT n * 6 FROM t WHERE k = ?".to_string());
+        let kvstore = new_sqlite_pool(&path, &query, 2).unwrap();
+        assert_eq!(Some(Value::from("42")), kvstore.get(b"k"));
+        // Bad queries are caught when opening.
+        let query = SqliteQuery::Select("SELECT nope FROM t WHERE k = ?".to_string());
+        assert!(new_sqlite_pool(&path, &query, 2).is_err());
+        // So are queries that don't take just the key.
+        for sql in &["SELECT v FROM t", "SELECT v FROM t WHERE k = ? AND n = ?"] {
+            let query = SqliteQuery::Select(sql.to_string());
+            let e = new_sqlite_pool(&path, &query, 2).err().unwrap();
+            assert!(e.to_string().contains(sql), "{}", e);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
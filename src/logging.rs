@@ -0,0 +1,78 @@
+//! cdbd's log, whose level can be changed as it runs
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use fern;
+use log::{self, LogLevelFilter, MaxLogLevelFilter};
+use time;
+
+use startup::StartupError;
+
+/// How much we log, which memcached's verbosity command changes
+pub struct LogLevel {
+    verbosity: AtomicUsize,
+    filter: Option<MaxLogLevelFilter>,
+}
+
+impl LogLevel {
+    /// A level that isn't connected to a logger, so changing it only changes the verbosity
+    /// reported.
+    #[cfg(test)]
+    pub fn detached(verbosity: usize) -> LogLevel {
+        LogLevel {
+            verbosity: AtomicUsize::new(verbosity),
+            filter: None,
+        }
+    }
+
+    pub fn verbosity(&self) -> usize {
+        self.verbosity.load(Ordering::Relaxed)
+    }
+
+    /// Log warnings at verbosity 0, information at 1, and everything from 2 up.
+    pub fn set_verbosity(&self, verbosity: usize) {
+        self.verbosity.store(verbosity, Ordering::Relaxed);
+        if let Some(ref filter) = self.filter {
+            filter.set(level_filter(verbosity));
+        }
+    }
+}
+
+fn level_filter(verbosity: usize) -> LogLevelFilter {
+    match verbosity {
+        0 => LogLevelFilter::Warn,
+        1 => LogLevelFilter::Info,
+        _ => LogLevelFilter::Trace,
+    }
+}
+
+/// Log to stdout at `verbosity`, returning the level to change it with later.
+pub fn setup_logger(verbosity: usize) -> Result<Arc<LogLevel>, StartupError> {
+    // The dispatch passes everything, leaving the log's max level to decide what's logged.
+    let (_, logger) = fern::Dispatch::new()
+        .format(|out, message, record| {
+            let t = time::now_utc();
+            out.finish(format_args!("[{}.{:03}Z][{}][{}] {}",
+                                    t.strftime("%FT%T").unwrap(),
+                                    t.tm_nsec / 1000000, // milliseconds
+                                    record.level(),
+                                    record.target(),
+                                    message))
+        })
+        .level(LogLevelFilter::Trace)
+        .chain(io::stdout())
+        .into_log();
+    let mut filter = None;
+    try!(log::set_logger(|max_level| {
+            max_level.set(level_filter(verbosity));
+            filter = Some(max_level);
+            logger
+        })
+        .map_err(|e| StartupError::setup("set up logging", e)));
+    Ok(Arc::new(LogLevel {
+        verbosity: AtomicUsize::new(verbosity),
+        filter: filter,
+    }))
+}
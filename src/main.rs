@@ -1,39 +1,144 @@
 use std::env;
 use std::fmt;
-use std::path::Path;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::mpsc;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
+extern crate base64;
 extern crate byteorder;
+extern crate bytes;
 extern crate fern;
+extern crate futures;
 extern crate getopts;
+#[cfg(target_os = "linux")]
+extern crate inotify;
 use getopts::{Matches, Options};
 #[macro_use]
 extern crate log;
+extern crate lmdb;
+extern crate memmap;
 extern crate mtbl;
 extern crate num_cpus;
 extern crate objpool;
 extern crate regex;
 use regex::Regex;
+extern crate rusqlite;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+extern crate signal_hook;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 extern crate time;
+extern crate tokio;
+extern crate tokio_threadpool;
+extern crate toml;
+#[cfg(test)]
 extern crate tinycdb;
 
+mod config;
+use config::read_config;
+
+mod http;
+use http::server::http_server;
+
 mod kvstore;
 use kvstore::KvStore;
-use kvstore::cdb::new_cdb_pool;
+use kvstore::cdb::{new_cdb, new_cdb64};
+use kvstore::lmdb::new_lmdb;
 use kvstore::mtbl::new_mtbl;
+use kvstore::overlay::OverlayKvStore;
+use kvstore::reload::{reload_on_replace, reload_on_sighup, ReloadableKvStore};
+use kvstore::routing::RoutingKvStore;
+use kvstore::sqlite::{new_sqlite_pool, SqliteQuery};
+
+mod logging;
+use logging::setup_logger;
 
 mod memcached;
-use memcached::server::memcached_server;
+use memcached::connections::{ConnectionLimit, OverLimit};
+use memcached::server::{memcached_server, MemcachedOptions};
+use memcached::stats::{unix_time, DbStats, Stats};
+use memcached::udp::memcached_udp_server;
+
+mod metrics;
+use metrics::{Collector, Kind, Metrics, Sample};
+
+mod admin;
+use admin::Health;
+use admin::server::admin_server;
+
+mod redis;
+use redis::server::redis_server;
+
+mod shutdown;
+use shutdown::Shutdown;
+
+mod startup;
+use startup::{StartupError, EXIT_ARGS};
+
+/// How long to let connections finish their requests once told to shut down, by default
+const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 30;
+
+/// The exit status when we had to stop before all connections were drained
+const EXIT_UNCLEAN_SHUTDOWN: i32 = 1;
+/// The exit status when a service stopped before we were told to shut down
+const EXIT_SERVICE_STOPPED: i32 = 7;
 
 
 /// A database to serve
 #[derive(Debug,Clone)]
 enum DbArg {
     Cdb(String),
+    Cdb64(String),
     Mtbl(String),
+    /// An LMDB environment, and optionally the name of the database in it to serve
+    Lmdb(String, Option<String>),
+    /// A SQLite database, and how to look keys up in it
+    Sqlite(String, SqliteQuery),
+    /// Several databases, each serving keys prefixed with "NAME:"
+    Namespaced(Vec<(String, DbArg)>),
+    /// A stack of databases, consulted in order; a key whose first hit is the tombstone value is
+    /// treated as missing
+    Overlay {
+        layers: Vec<DbArg>,
+        tombstone: Option<Vec<u8>>,
+    },
+}
+
+impl DbArg {
+    /// The files making up this database
+    fn paths(&self) -> Vec<PathBuf> {
+        match self {
+            &DbArg::Cdb(ref f) => vec![PathBuf::from(f)],
+            &DbArg::Cdb64(ref f) => vec![PathBuf::from(f)],
+            &DbArg::Mtbl(ref f) => vec![PathBuf::from(f)],
+            &DbArg::Lmdb(ref f, _) => vec![PathBuf::from(f)],
+            &DbArg::Sqlite(ref f, _) => vec![PathBuf::from(f)],
+            &DbArg::Namespaced(ref dbs) => dbs.iter().flat_map(|&(_, ref db)| db.paths()).collect(),
+            &DbArg::Overlay { ref layers, .. } => layers.iter().flat_map(|db| db.paths()).collect(),
+        }
+    }
+
+    /// The name of this database's type
+    fn format(&self) -> &'static str {
+        match self {
+            &DbArg::Cdb(_) => "cdb",
+            &DbArg::Cdb64(_) => "cdb64",
+            &DbArg::Mtbl(_) => "mtbl",
+            &DbArg::Lmdb(..) => "lmdb",
+            &DbArg::Sqlite(..) => "sqlite",
+            &DbArg::Namespaced(_) => "namespaced",
+            &DbArg::Overlay { .. } => "overlay",
+        }
+    }
 }
 
 /// A address and port to run a service on
@@ -52,74 +157,325 @@ impl fmt::Display for Listen {
 /// A service to run
 #[derive(Debug,Clone)]
 enum ServiceArg {
-    Memcached(Listen),
+    Memcached(Listen, MemcachedOptions),
+    /// memcached over UDP, which uses only the options' thread count
+    MemcachedUdp(Listen, MemcachedOptions),
+    Redis(Listen),
+    Http(Listen),
 }
 
 #[derive(Debug,Clone)]
 struct Args {
     db: DbArg,
     services: Vec<ServiceArg>,
+    /// Where to serve health checks and metrics
+    admin: Vec<Listen>,
+    /// How many handles to open for databases that need one per concurrent lookup
+    pool_size: usize,
+    /// How long to let connections finish their requests once told to shut down
+    drain_timeout: Duration,
     verbosity: u8,
 }
 
-fn parse_address_and_port(s: &str) -> Listen {
-    Regex::new(r"^((?P<address>.*):)?(?P<port>\d+)$")
+/// Parse "[HOST:]PORT", or describe what's wrong with it.
+fn parse_listen(s: &str) -> Result<Listen, String> {
+    let captures = try!(Regex::new(r"^((?P<address>.*):)?(?P<port>\d+)$")
         .unwrap()
         .captures(s)
-        .map(|c| {
-            Listen {
-                address: c.name("address").map_or("0.0.0.0", |m| m.as_str()).to_owned(),
-                port: c.name("port")
-                       .map(|m| {
-                           u16::from_str(m.as_str())
-                               .expect(&format!("error parsing port from \"{}\"", m.as_str()))
-                       })
-                       .unwrap(),
-            }
-        })
-        .expect(&format!("error parsing address and port from \"{}\"", s))
+        .ok_or(format!("error parsing address and port from \"{}\"", s)));
+    let port = try!(u16::from_str(&captures["port"])
+        .map_err(|_| format!("error parsing port from \"{}\"", &captures["port"])));
+    Ok(Listen {
+        address: captures.name("address").map_or("0.0.0.0", |m| m.as_str()).to_owned(),
+        port: port,
+    })
 }
 
-fn parse_services(matches: &Matches) -> Vec<ServiceArg> {
-    let services: Vec<ServiceArg> = vec![("memcached", ServiceArg::Memcached)]
-        .iter()
-        .map(|&(name, service_f)|
-             matches.opt_str(name)
-             .map(|s| service_f(parse_address_and_port(&s))))
-        // remove Nones
-        .flat_map(|o| o.into_iter())
-        .collect();
+fn parse_services(matches: &Matches) -> Result<Vec<ServiceArg>, String> {
+    let memcached = try!(parse_memcached_options(matches));
+    let memcached_udp = memcached.clone();
+    let service_matchers: Vec<(&str, Box<Fn(Listen) -> ServiceArg>)> =
+        vec![("memcached", Box::new(move |l| ServiceArg::Memcached(l, memcached.clone()))),
+             ("memcached-udp",
+              Box::new(move |l| ServiceArg::MemcachedUdp(l, memcached_udp.clone()))),
+             ("redis", Box::new(ServiceArg::Redis)),
+             ("http", Box::new(ServiceArg::Http))];
+    let mut services = Vec::new();
+    for &(name, ref service_f) in service_matchers.iter() {
+        if let Some(s) = matches.opt_str(name) {
+            services.push(service_f(try!(parse_listen(&s))));
+        }
+    }
     match services.len() {
-        0 => panic!("no services to run!"),
-        _ => services,
+        0 => Err("no services to run".to_string()),
+        _ => Ok(services),
+    }
+}
+
+/// The types of database file we serve, named as in their flags and config files
+fn db_types() -> Vec<(&'static str, fn(String) -> DbArg)> {
+    vec![("cdb", DbArg::Cdb),
+         ("cdb64", DbArg::Cdb64),
+         ("mtbl", DbArg::Mtbl),
+         ("lmdb", |f| DbArg::Lmdb(f, None)),
+         ("sqlite", |f| DbArg::Sqlite(f, default_sqlite_query()))]
+}
+
+/// Look keys up in the "key" column of table "kv", unless told otherwise
+fn default_sqlite_query() -> SqliteQuery {
+    SqliteQuery::Table {
+        table: "kv".to_string(),
+        key_column: "key".to_string(),
+        value_column: "value".to_string(),
+    }
+}
+
+/// How many handles to open for databases that need one per concurrent lookup, unless told
+/// otherwise
+fn default_pool_size() -> usize {
+    // Support a parallelism of 10 + 10 per CPU. Is that good? It seems like a start.
+    10 + 10 * num_cpus::get()
+}
+
+fn parse_sqlite_query(matches: &Matches) -> SqliteQuery {
+    match matches.opt_str("sqlite-query") {
+        Some(sql) => SqliteQuery::Select(sql),
+        None => {
+            SqliteQuery::Table {
+                table: matches.opt_str("sqlite-table").unwrap_or("kv".to_string()),
+                key_column: matches.opt_str("sqlite-key-column").unwrap_or("key".to_string()),
+                value_column: matches.opt_str("sqlite-value-column")
+                    .unwrap_or("value".to_string()),
+            }
+        }
     }
 }
 
-fn parse_db(matches: &Matches) -> DbArg {
-    let db_matchers: Vec<(&str, fn(String) -> DbArg)> = vec![("cdb", DbArg::Cdb),
-                                                             ("mtbl", DbArg::Mtbl)];
-    let mut dbs: Vec<DbArg> = db_matchers.iter()
+/// Parse "TYPE:PATH"; SQLite databases look keys up as the --sqlite-* flags say.
+fn parse_typed_db(s: &str, matches: &Matches) -> Result<DbArg, String> {
+    let captures = try!(Regex::new(r"^(?P<type>[^:]+):(?P<path>.+)$")
+        .unwrap()
+        .captures(s)
+        .ok_or(format!("error parsing TYPE:PATH from \"{}\"", s)));
+    let db_f = try!(db_types()
+        .into_iter()
+        .find(|&(name, _)| name == &captures["type"])
+        .map(|(_, db_f)| db_f)
+        .ok_or(format!("unknown database type \"{}\"", &captures["type"])));
+    Ok(match db_f(captures["path"].to_string()) {
+        DbArg::Sqlite(f, _) => DbArg::Sqlite(f, parse_sqlite_query(matches)),
+        db => db,
+    })
+}
+
+fn parse_namespaced_db(s: &str, matches: &Matches) -> Result<(String, DbArg), String> {
+    let captures = try!(Regex::new(r"^(?P<name>[^=:]+)=(?P<db>.*)$")
+        .unwrap()
+        .captures(s)
+        .ok_or(format!("error parsing NAME=TYPE:PATH from \"{}\"", s)));
+    Ok((captures["name"].to_string(), try!(parse_typed_db(&captures["db"], matches))))
+}
+
+fn parse_db(matches: &Matches) -> Result<DbArg, String> {
+    let mut dbs: Vec<DbArg> = db_types().iter()
         .map(|&(name, db_f)|
              matches.opt_str(name)
              .map(|s| db_f(s)))
         // remove Nones
         .flat_map(|o| o.into_iter())
+        .map(|db| match db {
+            DbArg::Lmdb(f, None) => DbArg::Lmdb(f, matches.opt_str("lmdb-db")),
+            DbArg::Sqlite(f, _) => DbArg::Sqlite(f, parse_sqlite_query(matches)),
+            db @ _ => db,
+        })
         .collect();
-    match dbs.len() {
-        1 => dbs.pop().unwrap(),
-        _ => panic!("Error: specify exactly one database file"),
+    let mut namespaces: Vec<(String, DbArg)> = Vec::new();
+    for s in matches.opt_strs("db") {
+        let (name, db) = try!(parse_namespaced_db(&s, matches));
+        if namespaces.iter().any(|&(ref n, _)| *n == name) {
+            return Err(format!("database namespace \"{}\" given more than once", name));
+        }
+        namespaces.push((name, db));
+    }
+    let db = match (dbs.len(), namespaces.len()) {
+        (1, 0) => dbs.pop().unwrap(),
+        (0, n) if n > 0 => DbArg::Namespaced(namespaces),
+        _ => {
+            return Err("specify exactly one database file, or one or more --db namespaces"
+                .to_string())
+        }
+    };
+    let mut layers: Vec<DbArg> = Vec::new();
+    for s in matches.opt_strs("overlay") {
+        layers.push(try!(parse_typed_db(&s, matches)));
+    }
+    let tombstone = matches.opt_str("tombstone").map(String::into_bytes);
+    match (layers.len(), &tombstone, &db) {
+        (0, &None, _) => Ok(db),
+        (_, _, &DbArg::Namespaced(_)) => {
+            Err("--overlay and --tombstone need a single base database file".to_string())
+        }
+        _ => {
+            layers.push(db);
+            Ok(DbArg::Overlay {
+                layers: layers,
+                tombstone: tombstone,
+            })
+        }
     }
 }
 
-fn parse_args() -> Args {
+fn parse_count(s: &str, what: &str) -> Result<usize, String> {
+    match usize::from_str(s) {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("error parsing {} from \"{}\"", what, s)),
+    }
+}
+
+fn parse_memcached_options(matches: &Matches) -> Result<MemcachedOptions, String> {
+    let threads = match matches.opt_str("threads") {
+        Some(s) => try!(parse_count(&s, "thread count")),
+        None => num_cpus::get(),
+    };
+    let over_limit = match matches.opt_str("over-limit") {
+        None => OverLimit::Queue,
+        Some(s) => {
+            match s.as_str() {
+                "queue" => OverLimit::Queue,
+                "reject" => OverLimit::Reject,
+                _ => {
+                    return Err(format!("--over-limit must be \"queue\" or \"reject\", not \
+                                        \"{}\"",
+                                       s))
+                }
+            }
+        }
+    };
+    let connection_limit = match matches.opt_str("max-connections") {
+        Some(s) => {
+            Some(ConnectionLimit {
+                max: try!(parse_count(&s, "connection limit")),
+                over_limit: over_limit,
+            })
+        }
+        None if matches.opt_present("over-limit") => {
+            return Err("--over-limit needs --max-connections".to_string())
+        }
+        None => None,
+    };
+    Ok(MemcachedOptions {
+        threads: threads,
+        connection_limit: connection_limit,
+    })
+}
+
+/// The flags whose settings a config file gives instead
+const CONFIGURED_FLAGS: &'static [&'static str] =
+    &["memcached", "memcached-udp", "redis", "http", "metrics", "admin", "cdb", "cdb64", "mtbl",
+      "lmdb", "lmdb-db", "sqlite", "sqlite-table", "sqlite-key-column", "sqlite-value-column",
+      "sqlite-query", "db", "overlay", "tombstone", "threads", "max-connections", "over-limit",
+      "drain-timeout"];
+
+fn parse_args() -> Result<Args, StartupError> {
     let mut opts = Options::new();
+    opts.optopt("",
+                "config",
+                "A TOML file describing the databases and services to run, instead of the \
+                 flags for them; see README.md",
+                "FILE");
     opts.optopt("",
                 "memcached",
                 "What port (and optional address) to bind a memcached service on (default \
                  address \"0.0.0.0\")",
                 "[HOST:]PORT");
+    opts.optopt("",
+                "memcached-udp",
+                "What port (and optional address) to bind a memcached UDP service on \
+                 (default address \"0.0.0.0\")",
+                "[HOST:]PORT");
+    opts.optopt("",
+                "redis",
+                "What port (and optional address) to bind a read-only Redis service on \
+                 (default address \"0.0.0.0\")",
+                "[HOST:]PORT");
+    opts.optopt("",
+                "http",
+                "What port (and optional address) to bind an HTTP service on (default \
+                 address \"0.0.0.0\")",
+                "[HOST:]PORT");
+    opts.optopt("",
+                "metrics",
+                "What port (and optional address) to serve Prometheus metrics on, at /metrics \
+                 (default address \"0.0.0.0\"); this also serves the --admin endpoints",
+                "[HOST:]PORT");
+    opts.optopt("",
+                "admin",
+                "What port (and optional address) to serve health checks on, at /healthz and \
+                 /readyz, along with metrics at /metrics (default address \"0.0.0.0\")",
+                "[HOST:]PORT");
     opts.optopt("", "cdb", "A CDB file to serve", "CDB");
+    opts.optopt("", "cdb64", "A cdb64 file (CDB with 64-bit offsets) to serve", "CDB64");
     opts.optopt("", "mtbl", "An MTBL file to serve", "MTBL");
+    opts.optopt("", "lmdb", "An LMDB environment (directory or file) to serve", "LMDB");
+    opts.optopt("",
+                "lmdb-db",
+                "The named database to serve from the --lmdb environment (default: the main \
+                 database)",
+                "NAME");
+    opts.optopt("", "sqlite", "A SQLite database file to serve", "SQLITE");
+    opts.optopt("",
+                "sqlite-table",
+                "The table to look keys up in for SQLite databases (default \"kv\")",
+                "TABLE");
+    opts.optopt("",
+                "sqlite-key-column",
+                "The column of keys in the SQLite table (default \"key\")",
+                "COLUMN");
+    opts.optopt("",
+                "sqlite-value-column",
+                "The column of values in the SQLite table (default \"value\")",
+                "COLUMN");
+    opts.optopt("",
+                "sqlite-query",
+                "A SELECT statement to look keys up with in SQLite databases instead, with the \
+                 key as its parameter, as in \"SELECT v FROM t WHERE k = ?\"",
+                "SQL");
+    opts.optmulti("",
+                  "db",
+                  "A database file to serve under a namespace, so that key \"NAME:k\" is looked \
+                   up as \"k\" in it; TYPE is cdb, cdb64, mtbl, lmdb or sqlite (may be used \
+                   more than once)",
+                  "NAME=TYPE:PATH");
+    opts.optmulti("",
+                  "overlay",
+                  "A database file to layer over the base file; keys are looked up in overlays \
+                   in the order given, then in the base file (may be used more than once)",
+                  "TYPE:PATH");
+    opts.optopt("",
+                "tombstone",
+                "A value that marks a key as deleted in an overlay, hiding it in the layers \
+                 below",
+                "VALUE");
+    opts.optopt("",
+                "threads",
+                "How many threads to serve memcached connections on, and to look keys up on \
+                 (default: one per CPU)",
+                "N");
+    opts.optopt("",
+                "max-connections",
+                "The most memcached connections to serve at once (default: no limit)",
+                "N");
+    opts.optopt("",
+                "over-limit",
+                "What to do with memcached connections beyond --max-connections: \"queue\" \
+                 them until others close, or \"reject\" them with a busy error (default \
+                 \"queue\")",
+                "POLICY");
+    opts.optopt("",
+                "drain-timeout",
+                "On SIGTERM or SIGINT, how long to let memcached connections finish their \
+                 requests before exiting anyway (default 30)",
+                "SECONDS");
     opts.optflagmulti("v",
                       "verbose",
                       "Print more logging information (may be used more than once for more \
@@ -127,87 +483,388 @@ fn parse_args() -> Args {
     opts.optflag("h", "help", "Print this help text");
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => m,
-        Err(f) => {
-            println!("{}", f.to_string());
-            exit(2);
-        }
-    };
+    let matches = try!(opts.parse(&args[1..]).map_err(|f| StartupError::Args(f.to_string())));
     if args.len() == 1 || matches.opt_present("help") {
         print!("{}", opts.usage(&format!("Usage: {} [options]", program)));
-        exit(2);
+        exit(EXIT_ARGS);
     }
     if !matches.free.is_empty() {
-        panic!("unexpected arguments");
+        return Err(StartupError::Args(format!("unexpected arguments: {}",
+                                              matches.free.join(" "))));
+    }
+    if let Some(path) = matches.opt_str("config") {
+        if let Some(flag) = CONFIGURED_FLAGS.iter().find(|flag| matches.opt_present(flag)) {
+            return Err(StartupError::Args(format!("--{} can't be used with --config", flag)));
+        }
+        let mut args = try!(read_config(Path::new(&path))
+            .map_err(|e| StartupError::Config(path.clone(), e)));
+        args.verbosity = args.verbosity.saturating_add(matches.opt_count("verbose") as u8);
+        return Ok(args);
+    }
+    parse_flags(&matches).map_err(StartupError::Args)
+}
+
+fn parse_flags(matches: &Matches) -> Result<Args, String> {
+    let mut admin = Vec::new();
+    for name in ["admin", "metrics"].iter() {
+        if let Some(s) = matches.opt_str(name) {
+            admin.push(try!(parse_listen(&s)));
+        }
     }
-    Args {
-        db: parse_db(&matches),
-        services: parse_services(&matches),
+    let drain_timeout = match matches.opt_str("drain-timeout") {
+        Some(s) => try!(parse_count(&s, "drain timeout")) as u64,
+        None => DEFAULT_DRAIN_TIMEOUT_SECS,
+    };
+    Ok(Args {
+        db: try!(parse_db(matches)),
+        services: try!(parse_services(matches)),
+        admin: admin,
+        pool_size: default_pool_size(),
+        drain_timeout: Duration::from_secs(drain_timeout),
         verbosity: matches.opt_count("verbose") as u8,
+    })
+}
+
+fn open_db(db: &DbArg, pool_size: usize) -> io::Result<Arc<KvStore + Send + Sync>> {
+    Ok(match db {
+        &DbArg::Cdb(ref f) => Arc::new(try!(new_cdb(Path::new(&f)))),
+        &DbArg::Cdb64(ref f) => Arc::new(try!(new_cdb64(Path::new(&f)))),
+        &DbArg::Mtbl(ref f) => Arc::new(try!(new_mtbl(Path::new(&f)))),
+        &DbArg::Lmdb(ref f, ref subdb) => {
+            Arc::new(try!(new_lmdb(Path::new(&f), subdb.as_ref().map(|s| s.as_str()))))
+        }
+        &DbArg::Sqlite(ref f, ref query) => {
+            Arc::new(try!(new_sqlite_pool(Path::new(&f), query, pool_size)))
+        }
+        &DbArg::Namespaced(ref dbs) => {
+            let mut namespaces = Vec::with_capacity(dbs.len());
+            for &(ref name, ref db) in dbs.iter() {
+                namespaces.push((name.clone(), try!(open_db(db, pool_size))));
+            }
+            Arc::new(RoutingKvStore::new(namespaces))
+        }
+        &DbArg::Overlay { ref layers, ref tombstone } => {
+            let mut kvstores = Vec::with_capacity(layers.len());
+            for db in layers.iter() {
+                kvstores.push(try!(open_db(db, pool_size)));
+            }
+            Arc::new(OverlayKvStore::new(kvstores, tombstone.clone()))
+        }
+    })
+}
+
+/// Open the database, reopening it on SIGHUP or when its files are replaced.
+fn open_reloadable_db(db: &DbArg,
+                      pool_size: usize)
+                      -> Result<Arc<ReloadableKvStore>, StartupError> {
+    let db_to_open = db.clone();
+    let kvstore = Arc::new(try!(ReloadableKvStore::new(move || open_db(&db_to_open, pool_size))
+        .map_err(StartupError::Open)));
+    try!(reload_on_sighup(kvstore.clone()).map_err(|e| StartupError::setup("handle SIGHUP", e)));
+    try!(reload_on_replace(kvstore.clone(), &db.paths())
+        .map_err(|e| StartupError::setup("watch database files", e)));
+    Ok(kvstore)
+}
+
+/// The size in bytes of a database file, or of the files in a database directory
+fn db_size(path: &Path) -> u64 {
+    match fs::metadata(path) {
+        Ok(ref metadata) if metadata.is_dir() => {
+            fs::read_dir(path)
+                .map(|entries| {
+                    entries.filter_map(|entry| entry.and_then(|e| e.metadata()).ok())
+                        .filter(|metadata| metadata.is_file())
+                        .map(|metadata| metadata.len())
+                        .sum()
+                })
+                .unwrap_or(0)
+        }
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
     }
 }
 
-fn setup_logger(verbosity: u8) {
-    fern::Dispatch::new()
-        .format(|out, message, record| {
-            let t = time::now_utc();
-            out.finish(format_args!("[{}.{:03}Z][{}][{}] {}",
-                                    t.strftime("%FT%T").unwrap(),
-                                    t.tm_nsec / 1000000, // milliseconds
-                                    record.level(),
-                                    record.target(),
-                                    message))
-        })
-        .level(match verbosity {
-            0 => log::LogLevelFilter::Warn,
-            1 => log::LogLevelFilter::Info,
-            _ => log::LogLevelFilter::Trace,
+/// Describe the database for the memcached stats command.
+fn db_stats(db: &DbArg, kvstore: Arc<ReloadableKvStore>) -> DbStats {
+    let format = db.format();
+    let paths = db.paths();
+    Arc::new(move || {
+        let path = paths.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join(",");
+        let size: u64 = paths.iter().map(|p| db_size(p)).sum();
+        vec![("db_path".to_string(), path),
+             ("db_format".to_string(), format.to_string()),
+             ("db_bytes".to_string(), size.to_string()),
+             ("db_loaded".to_string(), unix_time(kvstore.loaded()).to_string())]
+    })
+}
+
+/// The time a database file, or the newest file in a database directory, was last modified
+fn db_mtime(path: &Path) -> Option<SystemTime> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return None,
+    };
+    if !metadata.is_dir() {
+        return metadata.modified().ok();
+    }
+    fs::read_dir(path)
+        .ok()
+        .and_then(|entries| {
+            entries.filter_map(|entry| entry.and_then(|e| e.metadata()).ok())
+                .filter(|metadata| metadata.is_file())
+                .filter_map(|metadata| metadata.modified().ok())
+                .max()
         })
-        .chain(std::io::stdout())
-        .apply()
-        .expect("Failed to initialize global logger");
 }
 
-fn open_db(db: &DbArg) -> Arc<KvStore + Send + Sync> {
-    match db {
-        &DbArg::Cdb(ref f) => {
-            Arc::new(new_cdb_pool(Path::new(&f),
-                                  // Support a parallelism of 10 + 10 per CPU. Is
-                                  // that good? It seems like a start.
-                                  10 + 10 * num_cpus::get()))
+/// Report the database's generation and modification time as metrics.
+fn db_metrics(db: &DbArg, kvstore: Arc<ReloadableKvStore>) -> Collector {
+    let paths = db.paths();
+    Box::new(move || {
+        let mtime = paths.iter().filter_map(|p| db_mtime(p)).max();
+        vec![Sample {
+                 name: "cdbd_db_generation",
+                 help: "How many times the database has been reloaded",
+                 kind: Kind::Gauge,
+                 labels: vec![],
+                 value: kvstore.generation() as f64,
+             },
+             Sample {
+                 name: "cdbd_db_mtime_seconds",
+                 help: "When the database's newest file was modified, in Unix time",
+                 kind: Kind::Gauge,
+                 labels: vec![],
+                 value: mtime.map_or(0, unix_time) as f64,
+             }]
+    })
+}
+
+/// Something main waits on
+enum Event {
+    /// We were told to shut down by this signal
+    Signal(i32),
+    /// A service is listening
+    Bound,
+    /// A service couldn't start
+    Failed(StartupError),
+    /// A service's thread ended
+    Stopped {
+        /// Whether it's a service that finishes its work and stops when we shut down
+        drains: bool,
+        panicked: bool,
+    },
+}
+
+/// Reports the end of a service's thread, however it ends, as it's dropped
+struct StoppedGuard {
+    drains: bool,
+    events: mpsc::Sender<Event>,
+}
+
+impl Drop for StoppedGuard {
+    fn drop(&mut self) {
+        let _ = self.events.send(Event::Stopped {
+            drains: self.drains,
+            panicked: thread::panicking(),
+        });
+    }
+}
+
+/// What the services share
+#[derive(Clone)]
+struct Context {
+    kvstore: Arc<KvStore + Send + Sync>,
+    /// Counters shared by the memcached services
+    memcached_stats: Arc<Stats>,
+    metrics: Arc<Metrics>,
+    shutdown: Arc<Shutdown>,
+    events: mpsc::Sender<Event>,
+}
+
+impl ServiceArg {
+    /// Whether the service finishes its work and stops when we shut down; the others just stop
+    /// when we exit
+    fn drains(&self) -> bool {
+        match self {
+            &ServiceArg::Memcached(..) => true,
+            _ => false,
         }
-        &DbArg::Mtbl(ref f) => Arc::new(new_mtbl(Path::new(&f))),
     }
 }
 
-fn spawn_service(service: ServiceArg,
-                 db: &DbArg,
-                 kvstore: &Arc<KvStore + Send + Sync>)
-                 -> thread::JoinHandle<()> {
+fn spawn_service(service: ServiceArg, db: &DbArg, context: &Context) {
     println!("Serving from {:?} on {:?}", db, service);
-    let service = service.clone();
-    let kvstore = kvstore.clone();
-    thread::spawn(move || match service {
-        ServiceArg::Memcached(Listen { address, port }) => {
-            memcached_server(kvstore, &address, port);
+    let context = context.clone();
+    thread::spawn(move || {
+        let _stopped = StoppedGuard {
+            drains: service.drains(),
+            events: context.events.clone(),
+        };
+        let events = context.events.clone();
+        let bound = move || {
+            let _ = events.send(Event::Bound);
+        };
+        let result = match service {
+            ServiceArg::Memcached(Listen { address, port }, options) => {
+                memcached_server(context.kvstore,
+                                 &address,
+                                 port,
+                                 &options,
+                                 context.memcached_stats,
+                                 &context.shutdown,
+                                 &bound)
+            }
+            ServiceArg::MemcachedUdp(Listen { address, port }, options) => {
+                memcached_udp_server(context.kvstore,
+                                     &address,
+                                     port,
+                                     &options,
+                                     context.memcached_stats,
+                                     &bound)
+            }
+            ServiceArg::Redis(Listen { address, port }) => {
+                redis_server(context.kvstore, &address, port, context.metrics, &bound)
+            }
+            ServiceArg::Http(Listen { address, port }) => {
+                http_server(context.kvstore, &address, port, context.metrics, &bound)
+            }
+        };
+        if let Err(e) = result {
+            let _ = context.events.send(Event::Failed(e));
         }
-    })
+    });
+}
+
+/// Serve health checks and metrics on a thread.
+fn spawn_admin_service(listen: Listen,
+                       metrics: &Arc<Metrics>,
+                       health: &Arc<Health>,
+                       events: &mpsc::Sender<Event>) {
+    println!("Serving health checks and metrics on {}", listen);
+    let metrics = metrics.clone();
+    let health = health.clone();
+    let events = events.clone();
+    thread::spawn(move || {
+        let _stopped = StoppedGuard {
+            drains: false,
+            events: events.clone(),
+        };
+        if let Err(e) = admin_server(metrics, health, &listen.address, listen.port) {
+            let _ = events.send(Event::Failed(e));
+        }
+    });
+}
+
+/// Send an event when we're told to shut down with SIGTERM or SIGINT.
+fn forward_shutdown_signals(events: mpsc::Sender<Event>) -> io::Result<()> {
+    let mut signals = try!(Signals::new(&[SIGTERM, SIGINT]));
+    thread::spawn(move || for signal in signals.forever() {
+        if events.send(Event::Signal(signal)).is_err() {
+            return;
+        }
+    });
+    Ok(())
+}
+
+/// Wait for `draining` services to finish their work, then exit, with a status saying whether
+/// they all did in time.
+fn drain(events: mpsc::Receiver<Event>, mut draining: usize, timeout: Duration) -> ! {
+    let deadline = Instant::now() + timeout;
+    while draining > 0 {
+        let now = Instant::now();
+        let event = if now < deadline {
+            events.recv_timeout(deadline - now).ok()
+        } else {
+            None
+        };
+        match event {
+            Some(Event::Stopped { drains: true, panicked: false }) => draining -= 1,
+            Some(Event::Stopped { drains: false, .. }) |
+            Some(Event::Bound) => {}
+            Some(Event::Stopped { panicked: true, .. }) => {
+                error!("a service failed while draining");
+                exit(EXIT_UNCLEAN_SHUTDOWN);
+            }
+            Some(Event::Signal(signal)) => {
+                warn!("received signal {} again; exiting before connections are drained",
+                      signal);
+                exit(EXIT_UNCLEAN_SHUTDOWN);
+            }
+            Some(Event::Failed(e)) => {
+                error!("{}", e);
+                exit(EXIT_UNCLEAN_SHUTDOWN);
+            }
+            None => {
+                warn!("timed out after {}s waiting for connections to drain",
+                      timeout.as_secs());
+                exit(EXIT_UNCLEAN_SHUTDOWN);
+            }
+        }
+    }
+    info!("shut down cleanly");
+    exit(0);
+}
+
+/// Say why we couldn't start, and exit with the status for that.
+fn fail(error: StartupError) -> ! {
+    eprintln!("cdbd: {}", error);
+    exit(error.exit_code());
 }
 
 fn main() {
-    let Args { services, db, verbosity } = parse_args();
-    setup_logger(verbosity);
+    let Args { services, admin, db, pool_size, drain_timeout, verbosity } =
+        parse_args().unwrap_or_else(|e| fail(e));
+    let log_level = setup_logger(verbosity as usize).unwrap_or_else(|e| fail(e));
+    let metrics = Arc::new(Metrics::new());
+    let health = Arc::new(Health::new());
+    let (events_sender, events) = mpsc::channel();
+    // Start the admin services first, so they can report that we aren't ready while we load.
+    for listen in admin {
+        spawn_admin_service(listen, &metrics, &health, &events_sender);
+    }
     // Load the database.
-    let kvstore = open_db(&db);
-    // Start all services.
-    let threads: Vec<thread::JoinHandle<()>> = services.into_iter()
-                                                       .map(|service| {
-                                                           spawn_service(service, &db, &kvstore)
-                                                       })
-                                                       .collect();
-    // Wait on all server threads.
-    for thread in threads {
-        thread.join().expect("server thread failed");
+    let reloadable = open_reloadable_db(&db, pool_size).unwrap_or_else(|e| fail(e));
+    health.loaded(reloadable.clone());
+    metrics.add_collector(db_metrics(&db, reloadable.clone()));
+    let context = Context {
+        kvstore: reloadable.clone(),
+        memcached_stats: Stats::register(db_stats(&db, reloadable.clone()),
+                                         metrics.clone(),
+                                         log_level),
+        metrics: metrics,
+        shutdown: Arc::new(Shutdown::new()),
+        events: events_sender.clone(),
+    };
+    // Start all services, and report that we're ready once they're all listening.
+    let draining = services.iter().filter(|service| service.drains()).count();
+    let mut starting = services.len();
+    for service in services {
+        spawn_service(service, &db, &context);
+    }
+    forward_shutdown_signals(events_sender)
+        .unwrap_or_else(|e| fail(StartupError::setup("handle SIGTERM and SIGINT", e)));
+    // Serve until we're told to stop.
+    loop {
+        match events.recv().expect("lost track of services") {
+            Event::Bound => {
+                starting -= 1;
+                if starting == 0 {
+                    info!("all services started");
+                    health.started();
+                }
+            }
+            Event::Signal(signal) => {
+                info!("received signal {}; shutting down", signal);
+                break;
+            }
+            Event::Failed(e) => fail(e),
+            Event::Stopped { .. } => {
+                eprintln!("cdbd: a service stopped unexpectedly");
+                exit(EXIT_SERVICE_STOPPED);
+            }
+        }
     }
+    health.shut_down();
+    context.shutdown.begin();
+    drain(events, draining, drain_timeout);
 }
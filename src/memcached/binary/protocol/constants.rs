@@ -64,6 +64,8 @@ pub mod opcodes {
     pub const SASL_LIST_MECHS: u8 = 0x20;
     pub const SASL_AUTH: u8 = 0x21;
     pub const SASL_STEP: u8 = 0x22;
+    pub const GATK: u8 = 0x23;
+    pub const GATKQ: u8 = 0x24;
     pub const RGET: u8 = 0x30;
     pub const RSET: u8 = 0x31;
     pub const RSETQ: u8 = 0x32;
@@ -88,4 +90,47 @@ pub mod opcodes {
     pub const TAP_VBUCKET_SET: u8 = 0x45;
     pub const TAP_CHECKPOINT_START: u8 = 0x46;
     pub const TAP_CHECKPOINT_END: u8 = 0x47;
+
+    /// The opcode's name, as reported in metrics
+    pub fn name(opcode: u8) -> &'static str {
+        match opcode {
+            GET => "get",
+            SET => "set",
+            ADD => "add",
+            REPLACE => "replace",
+            DELETE => "delete",
+            INCREMENT => "increment",
+            DECREMENT => "decrement",
+            QUIT => "quit",
+            FLUSH => "flush",
+            GETQ => "getq",
+            NO_OP => "noop",
+            VERSION => "version",
+            GETK => "getk",
+            GETKQ => "getkq",
+            APPEND => "append",
+            PREPEND => "prepend",
+            STAT => "stat",
+            SETQ => "setq",
+            ADDQ => "addq",
+            REPLACEQ => "replaceq",
+            DELETEQ => "deleteq",
+            INCREMENTQ => "incrementq",
+            DECREMENTQ => "decrementq",
+            QUITQ => "quitq",
+            FLUSHQ => "flushq",
+            APPENDQ => "appendq",
+            PREPENDQ => "prependq",
+            VERBOSITY => "verbosity",
+            TOUCH => "touch",
+            GAT => "gat",
+            GATQ => "gatq",
+            GATK => "gatk",
+            GATKQ => "gatkq",
+            SASL_LIST_MECHS => "sasl_list_mechs",
+            SASL_AUTH => "sasl_auth",
+            SASL_STEP => "sasl_step",
+            _ => "other",
+        }
+    }
 }
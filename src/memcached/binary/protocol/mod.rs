@@ -1,6 +1,7 @@
 //! As described at
 //! https://github.com/memcached/memcached/wiki/BinaryProtocolRevamped
 
+use std::cmp;
 use std::io::{Read, Result, Write};
 
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
@@ -21,6 +22,14 @@ pub struct RequestHeader {
     pub cas: u64,
 }
 
+impl RequestHeader {
+    /// Whether this is a request header, with a body long enough for its extras and key
+    pub fn is_valid(&self) -> bool {
+        self.magic == constants::REQUEST_MAGIC &&
+        self.extras_length as u32 + self.key_length as u32 <= self.total_body_length
+    }
+}
+
 /// A Memcached binary request
 #[derive(Debug, PartialEq, Eq)]
 pub struct ARequest<T>
@@ -29,6 +38,7 @@ pub struct ARequest<T>
     pub header: RequestHeader,
     pub extras: T,
     pub key: T,
+    pub value: T,
 }
 
 pub type Request = ARequest<Vec<u8>>;
@@ -84,15 +94,21 @@ impl<T> PRead for T
         })
     }
 
+    /// Read a request with its whole body. The body is split into extras, key and value as the
+    /// header says, as far as it goes when the header isn't valid.
     fn read_request(self: &mut Self) -> Result<Request> {
         let header = try!(self.read_request_header());
-        let (mut extras, mut key) = (Vec::new(), Vec::new());
-        try!(self.take(header.extras_length as u64).read_to_end(&mut extras));
-        try!(self.take(header.key_length as u64).read_to_end(&mut key));
+        let mut body = vec![0; header.total_body_length as usize];
+        try!(self.read_exact(&mut body));
+        let key_start = cmp::min(header.extras_length as usize, body.len());
+        let mut key = body.split_off(key_start);
+        let value_start = cmp::min(header.key_length as usize, key.len());
+        let value = key.split_off(value_start);
         Ok(Request {
             header: header,
-            extras: extras,
+            extras: body,
             key: key,
+            value: value,
         })
     }
 
@@ -155,6 +171,7 @@ impl<W: Write> PWrite for W {
         try!(self.write_request_header(&request.header));
         try!(self.write(request.extras.as_ref()));
         try!(self.write(request.key.as_ref()));
+        try!(self.write(request.value.as_ref()));
         try!(self.flush());
         Ok(())
     }
@@ -217,6 +234,27 @@ impl<'a> Response<'a> {
         }
     }
 
+    /// Construct one response of a STAT sequence, which carries a stat's name and value; a
+    /// response with neither ends the sequence.
+    pub fn make_stat(request: &Request, key: &'a [u8], value: &'a [u8]) -> Response<'a> {
+        Response {
+            header: ResponseHeader {
+                magic: constants::RESPONSE_MAGIC,
+                opcode: request.header.opcode,
+                extras_length: 0,
+                data_type: constants::RAW_BYTES,
+                status: 0,
+                key_length: key.len() as u16,
+                total_body_length: (key.len() + value.len()) as u32,
+                opaque: request.header.opaque,
+                cas: 0,
+            },
+            extras: &[],
+            key: key,
+            value: value,
+        }
+    }
+
     /// Construct an error response.
     pub fn make_error(request: &Request, status_code: u16) -> Response<'a> {
         Response {
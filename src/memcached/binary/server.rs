@@ -1,65 +1,173 @@
-use std::io::{Read, Write};
+use std::io;
 
-use kvstore::KvStore;
+use byteorder::{BigEndian, ByteOrder};
 
-use super::protocol::{PRead, PWrite, Response};
+use kvstore::{KvStore, Value};
+use metrics::Outcome;
+
+use super::protocol::{PWrite, Request, Response};
 use super::protocol::constants::{opcodes, response_status};
+use super::super::codec::WriteValue;
 use super::super::error::Result;
+use super::super::stats::{version, Stats};
 
-pub fn handle_client<KV: KvStore, T: Read + PRead, U: Write>(kvstore: KV,
-                                                             mut ins: T,
-                                                             mut outs: U)
-                                                             -> Result<()> {
-    trace!("memcached_binary:connect");
-    loop {
-        let request = try!(ins.read_request());
-        let opcode = request.header.opcode;
-        match opcode {
-            opcodes::GET | opcodes::GETQ | opcodes::GETK | opcodes::GETKQ => {
-                let include_key = opcode == opcodes::GETK || opcode == opcodes::GETKQ;
-                let return_not_found = opcode == opcodes::GET || opcode == opcodes::GETK;
-                match kvstore.get(&request.key) {
-                    Some(data) => {
-                        trace!("memcached_binary:get {:?} => {} bytes",
-                               request.key,
-                               data.len());
-                        try!(outs.write_response(&Response::make(&request,
-                                                                 &[0x00, 0x00, 0x00, 0x00],
-                                                                 include_key,
-                                                                 &data)));
-                    }
-                    None => {
-                        trace!("memcached_binary:get {:?} => not found", request.key);
-                        if return_not_found {
-                            try!(outs.write_response(
-                                    &Response::make_error(&request,
-                                                          response_status::KEY_NOT_FOUND)));
-                        }
+/// The protocol name to report in metrics
+const PROTOCOL: &'static str = "memcached";
+
+/// The length of the expiration time that GAT and TOUCH requests carry as their extras
+const EXPIRATION_LENGTH: usize = 4;
+
+/// The length of the verbosity that VERBOSITY requests carry as their extras
+const VERBOSITY_LENGTH: usize = 4;
+
+/// Write the response to one request. Returns whether to keep the connection open.
+pub fn respond<KV: KvStore>(kvstore: &KV,
+                            stats: &Stats,
+                            request: &Request,
+                            mut outs: &mut WriteValue)
+                            -> Result<bool> {
+    let opcode = request.header.opcode;
+    let metrics = stats.metrics();
+    let name = opcodes::name(opcode);
+    if !request.header.is_valid() {
+        trace!("memcached_binary:invalid header {:?}", request.header);
+        metrics.record(PROTOCOL, name, Outcome::Error);
+        try!(outs.write_response(&Response::make_error(request,
+                                                       response_status::INVALID_ARGUMENTS)));
+        return Ok(true);
+    }
+    match opcode {
+        opcodes::GAT | opcodes::GATQ | opcodes::GATK | opcodes::GATKQ | opcodes::TOUCH
+            if request.extras.len() != EXPIRATION_LENGTH => {
+            trace!("memcached_binary:{} without an expiration time", name);
+            metrics.record(PROTOCOL, name, Outcome::Error);
+            try!(outs.write_response(&Response::make_error(request,
+                                                           response_status::INVALID_ARGUMENTS)));
+        }
+        opcodes::VERBOSITY if request.extras.len() != VERBOSITY_LENGTH => {
+            trace!("memcached_binary:verbosity without a level");
+            metrics.record(PROTOCOL, name, Outcome::Error);
+            try!(outs.write_response(&Response::make_error(request,
+                                                           response_status::INVALID_ARGUMENTS)));
+        }
+        // Our data never expires, so getting and touching is just getting.
+        opcodes::GET | opcodes::GETQ | opcodes::GETK | opcodes::GETKQ | opcodes::GAT |
+        opcodes::GATQ | opcodes::GATK | opcodes::GATKQ => {
+            let include_key = match opcode {
+                opcodes::GETK | opcodes::GETKQ | opcodes::GATK | opcodes::GATKQ => true,
+                _ => false,
+            };
+            let return_not_found = match opcode {
+                opcodes::GET | opcodes::GETK | opcodes::GAT | opcodes::GATK => true,
+                _ => false,
+            };
+            let value = metrics.time_lookup(PROTOCOL, || kvstore.get(&request.key));
+            stats.record_get(value.is_some());
+            metrics.record(PROTOCOL,
+                           name,
+                           if value.is_some() {
+                               Outcome::Hit
+                           } else {
+                               Outcome::Miss
+                           });
+            match value {
+                Some(data) => {
+                    trace!("memcached_binary:get {:?} => {} bytes",
+                           request.key,
+                           data.len());
+                    try!(write_value_response(outs,
+                                              &Response::make(request,
+                                                              &[0x00, 0x00, 0x00, 0x00],
+                                                              include_key,
+                                                              &data),
+                                              &data));
+                }
+                None => {
+                    trace!("memcached_binary:get {:?} => not found", request.key);
+                    if return_not_found {
+                        try!(outs.write_response(
+                                &Response::make_error(request,
+                                                      response_status::KEY_NOT_FOUND)));
                     }
                 }
             }
-            opcodes::QUIT => {
-                trace!("memcached_binary:quit");
-                break;
-            }
-            opcodes::NO_OP => {
-                trace!("memcached_binary:noop");
-                try!(outs.write_response(&Response::make(&request, &[], false, &[])));
-            }
-            opcodes::VERSION => {
-                trace!("memcached_binary:version");
-                try!(outs.write_response(&Response::make(&request,
-                                                         &[],
-                                                         false,
-                                                         "0.0.0".as_bytes())));
+        }
+        opcodes::TOUCH => {
+            let found = metrics.time_lookup(PROTOCOL, || kvstore.get(&request.key)).is_some();
+            trace!("memcached_binary:touch {:?} => {}",
+                   request.key,
+                   if found { "found" } else { "not found" });
+            if found {
+                metrics.record(PROTOCOL, name, Outcome::Hit);
+                try!(outs.write_response(&Response::make(request, &[], false, &[])));
+            } else {
+                metrics.record(PROTOCOL, name, Outcome::Miss);
+                try!(outs.write_response(&Response::make_error(request,
+                                                               response_status::KEY_NOT_FOUND)));
             }
-            _ => {
-                trace!("memcached_binary:unknown opcode {}", request.header.opcode);
-                try!(outs.write_response(&Response::make_error(&request,
-                                                               response_status::NOT_SUPPORTED)));
+        }
+        opcodes::STAT => {
+            let group = String::from_utf8_lossy(&request.key);
+            trace!("memcached_binary:stat {:?}", group);
+            match stats.group(&group) {
+                Some(group) => {
+                    for &(ref name, ref value) in group.iter() {
+                        try!(outs.write_response(&Response::make_stat(request,
+                                                                      name.as_bytes(),
+                                                                      value.as_bytes())));
+                    }
+                    try!(outs.write_response(&Response::make_stat(request, &[], &[])));
+                    metrics.record(PROTOCOL, name, Outcome::Ok);
+                }
+                None => {
+                    try!(outs.write_response(
+                            &Response::make_error(request, response_status::KEY_NOT_FOUND)));
+                    metrics.record(PROTOCOL, name, Outcome::Error);
+                }
             }
         }
-        try!(outs.flush());
+        opcodes::QUIT => {
+            trace!("memcached_binary:quit");
+            metrics.record(PROTOCOL, name, Outcome::Ok);
+            return Ok(false);
+        }
+        opcodes::NO_OP => {
+            trace!("memcached_binary:noop");
+            metrics.record(PROTOCOL, name, Outcome::Ok);
+            try!(outs.write_response(&Response::make(request, &[], false, &[])));
+        }
+        opcodes::VERSION => {
+            trace!("memcached_binary:version");
+            metrics.record(PROTOCOL, name, Outcome::Ok);
+            try!(outs.write_response(&Response::make(request,
+                                                     &[],
+                                                     false,
+                                                     version().as_bytes())));
+        }
+        opcodes::VERBOSITY => {
+            let level = BigEndian::read_u32(&request.extras);
+            trace!("memcached_binary:verbosity {}", level);
+            stats.log_level().set_verbosity(level as usize);
+            metrics.record(PROTOCOL, name, Outcome::Ok);
+            try!(outs.write_response(&Response::make(request, &[], false, &[])));
+        }
+        _ => {
+            trace!("memcached_binary:unknown opcode {}", request.header.opcode);
+            metrics.record(PROTOCOL, name, Outcome::Unsupported);
+            try!(outs.write_response(&Response::make_error(request,
+                                                           response_status::NOT_SUPPORTED)));
+        }
     }
-    Ok(())
+    Ok(true)
+}
+
+/// Write a response whose value is `value`, which `outs` may hold onto instead of copying.
+fn write_value_response(mut outs: &mut WriteValue,
+                        response: &Response,
+                        value: &Value)
+                        -> io::Result<()> {
+    try!(outs.write_response_header(&response.header));
+    try!(outs.write_all(response.extras));
+    try!(outs.write_all(response.key));
+    outs.write_value(value)
 }
@@ -0,0 +1,236 @@
+use std::io::{self, Cursor, Write};
+use std::mem;
+
+use bytes::BytesMut;
+use tokio::codec::{Decoder, Encoder};
+
+use kvstore::Value;
+use super::binary::protocol as binary;
+use super::binary::protocol::PRead;
+use super::binary::protocol::constants::REQUEST_MAGIC;
+use super::text::protocol as text;
+
+/// The longest text command line we'll wait for the end of
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// The most data we'll wait for after a text storage command, as memcached's default item size
+/// limit
+const MAX_DATA_LENGTH: usize = 1024 * 1024;
+
+/// The size of a binary request header
+const BINARY_HEADER_LENGTH: usize = 24;
+
+/// The longest binary request body we'll wait for: the longest value, with the longest extras
+/// and key a header can describe
+const MAX_BODY_LENGTH: usize = MAX_DATA_LENGTH + 0xff + 0xffff;
+
+/// A request in either memcached protocol
+#[derive(Debug)]
+pub enum Request {
+    Text(text::Request),
+    Binary(binary::Request),
+}
+
+/// Where responses are written, which may hold onto found values instead of copying them in
+pub trait WriteValue: Write {
+    /// Write a value found in the database.
+    fn write_value(&mut self, value: &Value) -> io::Result<()> {
+        self.write_all(value)
+    }
+}
+
+impl WriteValue for Vec<u8> {}
+
+/// A response to send on a connection. Values in memory-mapped files are kept as they are, and
+/// only copied once, when the response is encoded into the connection's write buffer.
+pub struct Output {
+    chunks: Vec<Value>,
+    /// What's been written since the last value kept
+    pending: Vec<u8>,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output {
+            chunks: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.chunks.iter().map(|chunk| chunk.len()).sum::<usize>() + self.pending.len()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WriteValue for Output {
+    fn write_value(&mut self, value: &Value) -> io::Result<()> {
+        match value {
+            &Value::Owned(ref v) => self.pending.extend_from_slice(v),
+            &Value::Mapped { ref mmap, start, end } => {
+                if !self.pending.is_empty() {
+                    let pending = mem::take(&mut self.pending);
+                    self.chunks.push(Value::Owned(pending));
+                }
+                self.chunks.push(Value::Mapped {
+                    mmap: mmap.clone(),
+                    start: start,
+                    end: end,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Frames memcached requests, in whichever protocol the client's first byte says it speaks.
+/// Responses are written by the protocols' servers, and encoded as they are.
+pub struct MemcachedCodec {
+    binary: Option<bool>,
+}
+
+impl MemcachedCodec {
+    pub fn new() -> MemcachedCodec {
+        MemcachedCodec { binary: None }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Take a text request off the front of `buf`. At the end of input, take whatever's left, which
+/// the text protocol parses as best it can.
+fn decode_text(buf: &mut BytesMut, eof: bool) -> io::Result<Option<Request>> {
+    let line_length = match buf.iter().position(|&c| c == b'\n') {
+        Some(i) => i + 1,
+        None if eof => buf.len(),
+        None if buf.len() > MAX_LINE_LENGTH => return Err(invalid("command line too long")),
+        None => return Ok(None),
+    };
+    let data_length = text::data_length(&String::from_utf8_lossy(&buf[..line_length]))
+        .unwrap_or(0);
+    if data_length > MAX_DATA_LENGTH {
+        return Err(invalid("value too long"));
+    }
+    if !eof && buf.len() < line_length + data_length {
+        return Ok(None);
+    }
+    let (request, consumed) = {
+        let mut cursor = Cursor::new(&buf[..]);
+        let request = text::Request::parse(&mut cursor);
+        (request, cursor.position() as usize)
+    };
+    buf.split_to(if eof { buf.len() } else { consumed });
+    Ok(Some(Request::Text(request)))
+}
+
+/// Take a binary request, with its whole body, off the front of `buf`. Requests whose headers
+/// aren't valid are still framed by their body length, so the server can answer them and go on.
+fn decode_binary(buf: &mut BytesMut) -> io::Result<Option<Request>> {
+    if buf.len() < BINARY_HEADER_LENGTH {
+        return Ok(None);
+    }
+    let header = try!(Cursor::new(&buf[..]).read_request_header());
+    if header.total_body_length as usize > MAX_BODY_LENGTH {
+        return Err(invalid("request body too long"));
+    }
+    let length = BINARY_HEADER_LENGTH + header.total_body_length as usize;
+    if buf.len() < length {
+        return Ok(None);
+    }
+    let request = try!(Cursor::new(&buf[..length]).read_request());
+    buf.split_to(length);
+    Ok(Some(Request::Binary(request)))
+}
+
+impl Decoder for MemcachedCodec {
+    type Item = Request;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        if *self.binary.get_or_insert(buf[0] == REQUEST_MAGIC) {
+            decode_binary(buf)
+        } else {
+            decode_text(buf, false)
+        }
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> io::Result<Option<Request>> {
+        match try!(self.decode(buf)) {
+            Some(request) => Ok(Some(request)),
+            None if buf.is_empty() => Ok(None),
+            None if self.binary == Some(false) => decode_text(buf, true),
+            None => {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-request"))
+            }
+        }
+    }
+}
+
+impl Encoder for MemcachedCodec {
+    type Item = Output;
+    type Error = io::Error;
+
+    fn encode(&mut self, response: Output, dst: &mut BytesMut) -> io::Result<()> {
+        dst.reserve(response.len());
+        for chunk in response.chunks.iter() {
+            dst.extend_from_slice(chunk);
+        }
+        dst.extend_from_slice(&response.pending);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use memmap::Mmap;
+    use tokio::codec::Encoder;
+
+    use kvstore::Value;
+    use super::{MemcachedCodec, Output, WriteValue};
+
+    #[test]
+    fn test_output_keeps_mapped_values() {
+        let path = env::temp_dir().join(format!("cdbd-test-output-{}", ::std::process::id()));
+        File::create(&path).unwrap().write_all(b"xvaluex").unwrap();
+        let mmap = Arc::new(unsafe { Mmap::map(&File::open(&path).unwrap()) }.unwrap());
+        fs::remove_file(&path).unwrap();
+        let mut output = Output::new();
+        output.write_all(b"VALUE k 0 5\r\n").unwrap();
+        output.write_value(&Value::Mapped {
+                mmap: mmap.clone(),
+                start: 1,
+                end: 6,
+            })
+            .unwrap();
+        output.write_all(b"\r\n").unwrap();
+        output.write_value(&Value::from("v")).unwrap();
+        // The mapped value is held, not copied, until the response is encoded.
+        assert_eq!(2, Arc::strong_count(&mmap));
+        assert_eq!(2, output.chunks.len());
+        let mut encoded = BytesMut::new();
+        MemcachedCodec::new().encode(output, &mut encoded).unwrap();
+        assert_eq!(&b"VALUE k 0 5\r\nvalue\r\nv"[..], &encoded[..]);
+        assert_eq!(1, Arc::strong_count(&mmap));
+    }
+}
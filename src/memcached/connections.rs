@@ -0,0 +1,236 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::{Async, Future, Poll, Stream};
+use futures::task::AtomicTask;
+
+/// The most connections over the limit to answer with a busy error at once; beyond this, they're
+/// closed as soon as they're accepted
+const MAX_REJECTING: usize = 100;
+
+/// What to do with connections beyond the limit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverLimit {
+    /// Stop accepting connections until some close, leaving new ones waiting in the listen
+    /// backlog
+    Queue,
+    /// Accept them, but answer their first request with a busy error and close them
+    Reject,
+}
+
+/// The most connections to serve at once, and what to do with more
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionLimit {
+    pub max: usize,
+    pub over_limit: OverLimit,
+}
+
+/// Counts a listener's connections, and holds back or turns away those over its limit.
+pub struct Connections {
+    limit: Option<ConnectionLimit>,
+    current: AtomicUsize,
+    total: AtomicUsize,
+    rejected: AtomicUsize,
+    /// How many rejected connections are waiting to be answered with a busy error
+    rejecting: AtomicUsize,
+    /// The accept loop, when it's waiting for a connection to close
+    waiting: AtomicTask,
+}
+
+/// A connection being served, which counts as open until this is dropped
+pub struct OpenConnection {
+    connections: Arc<Connections>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.connections.current.fetch_sub(1, Ordering::SeqCst);
+        self.connections.waiting.notify();
+    }
+}
+
+/// A connection over the limit being answered with a busy error, until this is dropped
+pub struct RejectingConnection {
+    connections: Arc<Connections>,
+}
+
+impl Drop for RejectingConnection {
+    fn drop(&mut self) {
+        self.connections.rejecting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Connections {
+    pub fn new(limit: Option<ConnectionLimit>) -> Connections {
+        Connections {
+            limit: limit,
+            current: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            rejected: AtomicUsize::new(0),
+            rejecting: AtomicUsize::new(0),
+            waiting: AtomicTask::new(),
+        }
+    }
+
+    /// The most connections to serve at once, if limited
+    pub fn limit(&self) -> Option<ConnectionLimit> {
+        self.limit
+    }
+
+    /// How many connections are open now
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::SeqCst)
+    }
+
+    /// How many connections have been served in all
+    pub fn total(&self) -> usize {
+        self.total.load(Ordering::SeqCst)
+    }
+
+    /// How many connections have been rejected for being over the limit
+    pub fn rejected(&self) -> usize {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    fn full(&self) -> bool {
+        self.limit.map_or(false, |limit| self.current() >= limit.max)
+    }
+
+    /// Count a newly accepted connection, or None if it's over the limit and should be rejected.
+    pub fn open(connections: &Arc<Connections>) -> Option<OpenConnection> {
+        if connections.full() {
+            connections.rejected.fetch_add(1, Ordering::SeqCst);
+            return None;
+        }
+        connections.current.fetch_add(1, Ordering::SeqCst);
+        connections.total.fetch_add(1, Ordering::SeqCst);
+        Some(OpenConnection { connections: connections.clone() })
+    }
+
+    /// Count a rejected connection as waiting for its busy error, or None if too many already
+    /// are, and it should just be closed.
+    pub fn reject(connections: &Arc<Connections>) -> Option<RejectingConnection> {
+        if connections.rejecting.fetch_add(1, Ordering::SeqCst) >= MAX_REJECTING {
+            connections.rejecting.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(RejectingConnection { connections: connections.clone() })
+    }
+}
+
+/// Accepts connections from `incoming`, unless the limit says to queue them and it's been reached.
+pub struct Accept<S> {
+    incoming: S,
+    connections: Arc<Connections>,
+    /// Whether connections are waiting for others to close
+    queueing: bool,
+}
+
+impl<S> Accept<S> {
+    pub fn new(incoming: S, connections: Arc<Connections>) -> Accept<S> {
+        Accept {
+            incoming: incoming,
+            connections: connections,
+            queueing: false,
+        }
+    }
+}
+
+impl<S: Stream> Stream for Accept<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        let limit = match self.connections.limit {
+            Some(limit) if limit.over_limit == OverLimit::Queue => limit,
+            _ => return self.incoming.poll(),
+        };
+        if self.connections.full() {
+            // Check again after registering, in case a connection closed in between.
+            self.connections.waiting.register();
+            if self.connections.full() {
+                if !self.queueing {
+                    warn!("memcached connection limit of {} reached; queueing new connections",
+                          limit.max);
+                    self.queueing = true;
+                }
+                return Ok(Async::NotReady);
+            }
+        }
+        if self.queueing {
+            info!("memcached connections below limit; accepting again");
+            self.queueing = false;
+        }
+        self.incoming.poll()
+    }
+}
+
+/// Ends a stream once `until` succeeds.
+///
+/// On a connection's stream of requests, this stops it between requests when we shut down: a
+/// request already being answered finishes, and an idle connection closes.
+pub struct Until<S, F> {
+    stream: S,
+    until: F,
+}
+
+impl<S, F> Until<S, F> {
+    pub fn new(stream: S, until: F) -> Until<S, F> {
+        Until {
+            stream: stream,
+            until: until,
+        }
+    }
+}
+
+impl<S: Stream, F: Future> Stream for Until<S, F> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, S::Error> {
+        match self.until.poll() {
+            Ok(Async::Ready(_)) => Ok(Async::Ready(None)),
+            _ => self.stream.poll(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use super::{ConnectionLimit, Connections, OverLimit, MAX_REJECTING};
+
+    #[test]
+    fn test_counts() {
+        let connections = Arc::new(Connections::new(Some(ConnectionLimit {
+            max: 2,
+            over_limit: OverLimit::Reject,
+        })));
+        let a = Connections::open(&connections).unwrap();
+        let b = Connections::open(&connections).unwrap();
+        assert!(Connections::open(&connections).is_none());
+        assert_eq!((2, 2, 1),
+                   (connections.current(), connections.total(), connections.rejected()));
+        drop(a);
+        let c = Connections::open(&connections).unwrap();
+        assert_eq!((2, 3, 1),
+                   (connections.current(), connections.total(), connections.rejected()));
+        drop(b);
+        drop(c);
+        assert_eq!(0, connections.current());
+    }
+
+    #[test]
+    fn test_rejecting() {
+        let connections = Arc::new(Connections::new(Some(ConnectionLimit {
+            max: 0,
+            over_limit: OverLimit::Reject,
+        })));
+        let rejecting: Vec<_> =
+            (0..MAX_REJECTING).map(|_| Connections::reject(&connections).unwrap()).collect();
+        assert!(Connections::reject(&connections).is_none());
+        drop(rejecting);
+        assert!(Connections::reject(&connections).is_some());
+    }
+}
@@ -1,4 +1,8 @@
 pub mod binary;
+pub mod codec;
+pub mod connections;
 pub mod error;
 pub mod server;
+pub mod stats;
 pub mod text;
+pub mod udp;
@@ -1,91 +1,292 @@
-use std::io::{Cursor, Read, BufReader, BufWriter};
-use std::net::{TcpListener, TcpStream};
-use std::thread;
+use std::io;
+use std::net;
+use std::result;
+use std::sync::Arc;
+use std::time::Duration;
 
-use byteorder::ReadBytesExt;
+use futures::{future, Future, Sink, Stream};
+use futures::future::Shared;
+use futures::sync::oneshot;
+use tokio;
+use tokio::codec::Decoder;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::reactor::Handle;
+use tokio::runtime;
+use tokio::timer::Timeout;
+use tokio_threadpool::blocking;
 
 use kvstore::KvStore;
-use super::binary::protocol::constants as binary_constants;
+use metrics::connections::Counted;
+use shutdown::Shutdown;
+use startup::StartupError;
+use super::binary::protocol::{PWrite, Response};
+use super::binary::protocol::constants::response_status;
 use super::binary::server as binary_server;
+use super::codec::{MemcachedCodec, Output, Request, WriteValue};
+use super::connections::{Accept, ConnectionLimit, Connections, Until};
 use super::error::Result;
+use super::stats::Stats;
+use super::text::protocol::Response as TextResponse;
 use super::text::server as text_server;
 
-pub fn memcached_server<KV>(kvstore: KV, host: &str, port: u16)
+/// How long to wait for a rejected connection's first request before closing it anyway
+const REJECT_TIMEOUT_MILLIS: u64 = 500;
+
+/// How to run a memcached service
+#[derive(Debug, Clone)]
+pub struct MemcachedOptions {
+    /// How many threads to serve connections on, and how many to look keys up on
+    pub threads: usize,
+    /// The most connections to serve at once, if limited
+    pub connection_limit: Option<ConnectionLimit>,
+}
+
+/// Serve memcached on `host`:`port`, calling `bound` once it's listening.
+pub fn memcached_server<KV>(kvstore: KV,
+                            host: &str,
+                            port: u16,
+                            options: &MemcachedOptions,
+                            stats: Arc<Stats>,
+                            shutdown: &Shutdown,
+                            bound: &Fn())
+                            -> result::Result<(), StartupError>
     where KV: KvStore,
           KV: Clone,
           KV: Send,
+          KV: Sync,
           KV: 'static
 {
-    let listener = TcpListener::bind((host, port)).expect(&format!("Failed to open port {}", port));
-
-    // accept connections and process them, spawning a new thread for each one
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                // connection succeeded
-                let kvs = kvstore.clone();
-                thread::spawn(move || handle_client(kvs, stream));
-            }
+    let listener = try!(net::TcpListener::bind((host, port))
+        .map_err(|e| StartupError::bind("memcached", host, port, e)));
+    bound();
+    serve(kvstore, listener, options, stats, shutdown)
+}
+
+/// Serve connections from `listener` on an async runtime, which this blocks to run.
+///
+/// Once shutdown begins, this stops accepting connections, lets each open one finish the request
+/// it's answering, closes them all, and returns.
+fn serve<KV>(kvstore: KV,
+             listener: net::TcpListener,
+             options: &MemcachedOptions,
+             stats: Arc<Stats>,
+             shutdown: &Shutdown)
+             -> result::Result<(), StartupError>
+    where KV: KvStore + Clone + Send + Sync + 'static
+{
+    let listener = try!(TcpListener::from_std(listener, &Handle::default())
+        .map_err(|e| StartupError::setup("register the memcached listener", e)));
+    let connections = Arc::new(Connections::new(options.connection_limit));
+    stats.add_service(options.threads, Some(connections.clone()));
+    let incoming = listener.incoming()
+        .then(|stream| match stream {
+            Ok(stream) => Ok::<_, ()>(Some(stream)),
             Err(_) => {
                 trace!("connection failed as it was received");
+                Ok(None)
+            }
+        })
+        .filter_map(|stream| stream);
+    let shutdown_started = shutdown.started();
+    let accept = Until::new(Accept::new(incoming, connections.clone()), shutdown.started());
+    let server = accept.for_each(move |stream| {
+        match Connections::open(&connections) {
+            Some(connection) => {
+                let client = handle_client(kvstore.clone(),
+                                           stats.clone(),
+                                           shutdown_started.clone(),
+                                           stream);
+                tokio::spawn(client.then(move |result| {
+                    drop(connection);
+                    result
+                }));
+            }
+            None => {
+                match Connections::reject(&connections) {
+                    Some(rejecting) => {
+                        let reject = reject_client(stream,
+                                                   stats.clone(),
+                                                   shutdown_started.clone(),
+                                                   &connections);
+                        tokio::spawn(reject.then(move |result| {
+                            drop(rejecting);
+                            result
+                        }));
+                    }
+                    None => trace!("too many connections being rejected; closing another"),
+                }
             }
         }
-    }
+        Ok(())
+    });
+    let mut runtime = try!(runtime::Builder::new()
+        .core_threads(options.threads)
+        .blocking_threads(options.threads)
+        .name_prefix("memcached-")
+        .build()
+        .map_err(|e| StartupError::setup("start memcached threads", e)));
+    runtime.block_on(server).expect("memcached server failed");
+    info!("memcached stopped accepting connections; draining");
+    runtime.shutdown_on_idle().wait().expect("memcached server failed to drain");
+    info!("memcached drained");
+    Ok(())
 }
 
-fn handle_client<KV: KvStore>(kvstore: KV, mut stream: TcpStream) -> Result<()> {
-    let first_char: u8 = try!(stream.read_u8());
-    let fake_stream = Cursor::new(vec![first_char]);
-    let addr = try!(stream.peer_addr());
-    let peeked = BufReader::new(fake_stream.chain(stream.try_clone().unwrap()));
-    let writer = BufWriter::new(stream);
-    let binary = first_char == binary_constants::REQUEST_MAGIC;
-    let protocol_name = match binary {
-        true => "memcached_binary",
-        false => "memcached_text",
+fn handle_client<KV>(kvstore: KV,
+                     stats: Arc<Stats>,
+                     shutdown_started: Shared<oneshot::Receiver<()>>,
+                     stream: TcpStream)
+                     -> Box<Future<Item = (), Error = ()> + Send>
+    where KV: KvStore + Clone + Send + Sync + 'static
+{
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return Box::new(future::ok(())),
     };
-    info!("{} connection from {}", protocol_name, addr);
-    let result = match binary {
-        true => binary_server::handle_client(kvstore, peeked, writer),
-        _ => text_server::handle_client(kvstore, peeked, writer),
+    info!("memcached connection from {}", addr);
+    let stream = Counted::new(stream, stats.bytes());
+    let (outs, ins) = MemcachedCodec::new().framed(stream).split();
+    let responses = Until::new(ins, shutdown_started).and_then(move |request| {
+            let kvstore = kvstore.clone();
+            let stats = stats.clone();
+            // Lookups can block on a slow disk, so answer each request on a thread that's allowed
+            // to block, not on one running the reactor.
+            future::poll_fn(move || {
+                    blocking(|| {
+                        let mut response = Output::new();
+                        respond(&kvstore, &stats, &request, &mut response)
+                            .map(|keep_open| (response, keep_open))
+                    })
+                })
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .and_then(|result| {
+                    result.map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+                })
+        })
+        .take_while(|&(_, keep_open)| Ok(keep_open))
+        .map(|(response, _)| response);
+    Box::new(outs.send_all(responses).then(move |result| {
+        if let Err(e) = result {
+            trace!("memcached error from {}: {}", addr, e);
+        }
+        info!("memcached disconnection from {}", addr);
+        Ok(())
+    }))
+}
+
+/// Answer a connection over the limit's first request with a busy error, then close it.
+fn reject_client(stream: TcpStream,
+                 stats: Arc<Stats>,
+                 shutdown_started: Shared<oneshot::Receiver<()>>,
+                 connections: &Connections)
+                 -> Box<Future<Item = (), Error = ()> + Send> {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return Box::new(future::ok(())),
     };
-    info!("{} disconnection from {}", protocol_name, addr);
-    result
+    warn!("memcached connection from {} rejected: {} connections open, {} rejected in all",
+          addr,
+          connections.current(),
+          connections.rejected());
+    let (outs, ins) = MemcachedCodec::new().framed(Counted::new(stream, stats.bytes())).split();
+    let responses = Until::new(ins, shutdown_started).take(1).and_then(|request| {
+        respond_busy(&request)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))
+    });
+    let reject = outs.send_all(responses);
+    Box::new(Timeout::new(reject, Duration::from_millis(REJECT_TIMEOUT_MILLIS)).then(move |result| {
+        if result.is_err() {
+            trace!("memcached error from rejected {}", addr);
+        }
+        Ok(())
+    }))
+}
+
+/// Answer a request with a busy error.
+fn respond_busy(request: &Request) -> Result<Output> {
+    let mut response = Output::new();
+    match request {
+        &Request::Text(_) => try!(TextResponse::ServerError("busy").write(&mut response)),
+        &Request::Binary(ref request) => {
+            try!(response.write_response(&Response::make_error(request, response_status::BUSY)))
+        }
+    }
+    Ok(response)
+}
+
+/// Write the answer to one request to `response`, returning whether to keep the connection
+/// open.
+pub fn respond<KV: KvStore>(kvstore: &KV,
+                            stats: &Stats,
+                            request: &Request,
+                            response: &mut WriteValue)
+                            -> Result<bool> {
+    match request {
+        &Request::Text(ref request) => text_server::respond(kvstore, stats, request, response),
+        &Request::Binary(ref request) => binary_server::respond(kvstore, stats, request, response),
+    }
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{Read, Write};
     use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::Arc;
     use std::thread;
 
-    use kvstore::KvStore;
+    use kvstore::{KvStore, Value};
+    use logging::LogLevel;
+    use metrics::Metrics;
+    use shutdown::Shutdown as ServerShutdown;
+    use super::MemcachedOptions;
+    use super::super::connections::{ConnectionLimit, OverLimit};
+    use super::super::stats::{version, Stats};
     use super::super::binary::protocol::{constants, Request, RequestHeader, AResponse,
                                          ResponseHeader, PRead, PWrite};
 
     /// A KvStore with one pair, {"k": "v"}
+    #[derive(Clone)]
     struct DummyKvStore {
     }
 
     impl KvStore for DummyKvStore {
-        fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        fn get(&self, key: &[u8]) -> Option<Value> {
             if key == "k".as_bytes() {
-                Some("v".as_bytes().to_vec())
+                Some(Value::from("v"))
             } else {
                 None
             }
         }
     }
 
-    fn make_server_conn() -> TcpStream {
+    /// Start a server, returning its port and its thread.
+    fn start_server(connection_limit: Option<ConnectionLimit>,
+                    shutdown: Arc<ServerShutdown>)
+                    -> (u16, thread::JoinHandle<()>) {
         let listener = TcpListener::bind(("localhost", 0)).unwrap();
         let port = listener.local_addr().unwrap().port();
-        let client_conn = TcpStream::connect(("localhost", port)).unwrap();
-        thread::spawn(move || {
-            let (server_stream, _) = listener.accept().unwrap();
-            super::handle_client(DummyKvStore {}, server_stream).unwrap_or(());
+        let options = MemcachedOptions {
+            threads: 2,
+            connection_limit: connection_limit,
+        };
+        let server = thread::spawn(move || {
+            let stats = Stats::register(Arc::new(|| {
+                                            vec![("db_path".to_string(), "dummy".to_string())]
+                                        }),
+                                        Arc::new(Metrics::new()),
+                                        Arc::new(LogLevel::detached(0)));
+            super::serve(DummyKvStore {}, listener, &options, stats, &shutdown)
+                .unwrap()
         });
-        client_conn
+        (port, server)
+    }
+
+    fn make_server(connection_limit: Option<ConnectionLimit>) -> u16 {
+        start_server(connection_limit, Arc::new(ServerShutdown::new())).0
+    }
+
+    fn make_server_conn() -> TcpStream {
+        TcpStream::connect(("localhost", make_server(None))).unwrap()
     }
 
     #[test]
@@ -99,6 +300,41 @@ mod test {
         assert_eq!("ERROR\r\n", response);
     }
 
+    #[test]
+    fn test_reject_over_limit() {
+        let port = make_server(Some(ConnectionLimit {
+            max: 1,
+            over_limit: OverLimit::Reject,
+        }));
+        let mut first = TcpStream::connect(("localhost", port)).unwrap();
+        // Wait for the first connection to be served, so the second is over the limit.
+        first.write("get k\r\n".as_bytes()).unwrap();
+        let mut response = [0; 21];
+        first.read_exact(&mut response).unwrap();
+        let mut second = TcpStream::connect(("localhost", port)).unwrap();
+        second.write("get k\r\n".as_bytes()).unwrap();
+        let mut response = String::new();
+        second.read_to_string(&mut response).unwrap();
+        assert_eq!("SERVER_ERROR busy\r\n", response);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let shutdown = Arc::new(ServerShutdown::new());
+        let (port, server) = start_server(None, shutdown.clone());
+        let mut client_stream = TcpStream::connect(("localhost", port)).unwrap();
+        client_stream.write("get k\r\n".as_bytes()).unwrap();
+        let mut response = [0; 21];
+        client_stream.read_exact(&mut response).unwrap();
+        // On shutdown, idle connections are closed, and the server stops.
+        shutdown.begin();
+        let mut response = String::new();
+        client_stream.read_to_string(&mut response).unwrap();
+        assert_eq!("", response);
+        server.join().unwrap();
+        assert!(TcpStream::connect(("localhost", port)).is_err());
+    }
+
     #[test]
     fn test_text_key_present() {
         let mut client_stream = make_server_conn();
@@ -121,6 +357,105 @@ mod test {
         assert_eq!("END\r\n", response);
     }
 
+    #[test]
+    fn test_text_stats() {
+        let mut client_stream = make_server_conn();
+        client_stream.write("get k _\r\n".as_bytes()).unwrap();
+        let mut response = [0; 21];
+        client_stream.read_exact(&mut response).unwrap();
+        assert_eq!(b"VALUE k 0 1\r\nv\r\nEND\r\n", &response);
+        client_stream.write("stats\r\n".as_bytes()).unwrap();
+        let mut response = Vec::new();
+        while !response.ends_with(b"END\r\n") {
+            let mut buf = [0; 1024];
+            let n = client_stream.read(&mut buf).unwrap();
+            response.extend_from_slice(&buf[..n]);
+        }
+        client_stream.write("stats nonsense\r\n".as_bytes()).unwrap();
+        client_stream.shutdown(Shutdown::Write).unwrap();
+        client_stream.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("STAT pid "));
+        for stat in &["STAT curr_connections 1\r\n",
+                      "STAT total_connections 1\r\n",
+                      "STAT cmd_get 2\r\n",
+                      "STAT get_hits 1\r\n",
+                      "STAT get_misses 1\r\n",
+                      "STAT bytes_read 16\r\n",
+                      "STAT bytes_written 21\r\n",
+                      "STAT threads 2\r\n",
+                      "STAT db_path dummy\r\n"] {
+            assert!(response.contains(stat), "{:?} missing from {:?}", stat, response);
+        }
+        assert!(response.ends_with("END\r\nERROR\r\n"));
+    }
+
+    #[test]
+    fn test_text_gat_and_touch() {
+        let mut client_stream = make_server_conn();
+        client_stream.write("gat 3600 k _\r\ngats 3600 k\r\ntouch k 3600\r\ntouch _ 3600\r\n\
+                             touch k 3600 noreply\r\ngat k\r\n"
+                .as_bytes())
+            .unwrap();
+        client_stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client_stream.read_to_string(&mut response).unwrap();
+        assert_eq!("VALUE k 0 1\r\nv\r\nEND\r\nVALUE k 0 1 0\r\nv\r\nEND\r\nTOUCHED\r\n\
+                    NOT_FOUND\r\nERROR\r\n",
+                   response);
+    }
+
+    #[test]
+    fn test_text_version_and_verbosity() {
+        let mut client_stream = make_server_conn();
+        client_stream.write("version\r\nverbosity 2\r\nverbosity 1 noreply\r\nverbosity x\r\n\
+                             stats settings\r\n"
+                .as_bytes())
+            .unwrap();
+        client_stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client_stream.read_to_string(&mut response).unwrap();
+        let expected = format!("VERSION {}\r\nOK\r\nERROR\r\n", version());
+        assert!(response.starts_with(&expected), "{:?}", response);
+        assert!(response.contains("STAT verbosity 1\r\n"), "{:?}", response);
+    }
+
+    #[test]
+    fn test_text_meta() {
+        let mut client_stream = make_server_conn();
+        client_stream.write(concat!("mg k v k s f t Oxy\r\n",
+                                    "mg k\r\n",
+                                    "mg _ k Oxy\r\n",
+                                    "mg aw== b k v\r\n",
+                                    "mg _ v q\r\n",
+                                    "mn\r\n",
+                                    "me k\r\n",
+                                    "me _\r\n",
+                                    "mg k x\r\n",
+                                    "mg k é\r\n",
+                                    "mg\r\n",
+                                    "ms k 2 T0\r\nhi\r\n",
+                                    "md k q\r\n")
+                .as_bytes())
+            .unwrap();
+        client_stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client_stream.read_to_string(&mut response).unwrap();
+        assert_eq!(concat!("VA 1 kk s1 f0 t-1 Oxy\r\nv\r\n",
+                           "HD\r\n",
+                           "EN k_ Oxy\r\n",
+                           "VA 1 b kaw==\r\nv\r\n",
+                           "MN\r\n",
+                           "ME k exp=-1 cas=0 size=1\r\n",
+                           "EN\r\n",
+                           "CLIENT_ERROR invalid flag\r\n",
+                           "CLIENT_ERROR invalid flag\r\n",
+                           "CLIENT_ERROR bad command line format\r\n",
+                           "SERVER_ERROR Read-only; method not implemented\r\n",
+                           "SERVER_ERROR Read-only; method not implemented\r\n"),
+                   response);
+    }
+
     #[test]
     fn test_text_not_implemented() {
         let mut client_stream = make_server_conn();
@@ -144,12 +479,13 @@ mod test {
                              extras_length: 0,
                              data_type: 0x00,
                              reserved: 0,
-                             total_body_length: 0,
+                             total_body_length: 1,
                              opaque: 0,
                              cas: 0,
                          },
                          extras: vec![],
                          key: vec!['k' as u8],
+                         value: vec![],
                      })
                      .unwrap();
         let response = client_stream.read_response().unwrap();
@@ -172,6 +508,57 @@ mod test {
                    response);
     }
 
+    fn stat_request(group: &str) -> Request {
+        Request {
+            header: RequestHeader {
+                magic: constants::REQUEST_MAGIC,
+                opcode: constants::opcodes::STAT,
+                key_length: group.len() as u16,
+                extras_length: 0,
+                data_type: 0x00,
+                reserved: 0,
+                total_body_length: group.len() as u32,
+                opaque: 7,
+                cas: 0,
+            },
+            extras: vec![],
+            key: group.as_bytes().to_vec(),
+            value: vec![],
+        }
+    }
+
+    #[test]
+    fn test_binary_stat() {
+        let mut client_stream = make_server_conn();
+        // All stats come back, one per response, ending with an empty one.
+        client_stream.write_request(&stat_request("")).unwrap();
+        let mut stats = Vec::new();
+        loop {
+            let response = client_stream.read_response().unwrap();
+            assert_eq!((constants::opcodes::STAT, constants::response_status::NO_ERROR, 7),
+                       (response.header.opcode, response.header.status, response.header.opaque));
+            if response.key.is_empty() {
+                break;
+            }
+            stats.push((String::from_utf8(response.key).unwrap(),
+                        String::from_utf8(response.value).unwrap()));
+        }
+        assert!(stats.contains(&("curr_connections".to_string(), "1".to_string())));
+        assert!(stats.contains(&("db_path".to_string(), "dummy".to_string())));
+        // So do groups of them.
+        client_stream.write_request(&stat_request("settings")).unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!((b"num_threads".to_vec(), b"2".to_vec()), (response.key, response.value));
+        let response = client_stream.read_response().unwrap();
+        assert_eq!((b"verbosity".to_vec(), b"0".to_vec()), (response.key, response.value));
+        let response = client_stream.read_response().unwrap();
+        assert_eq!(0, response.header.total_body_length);
+        // Unknown groups aren't found.
+        client_stream.write_request(&stat_request("nonsense")).unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!(constants::response_status::KEY_NOT_FOUND, response.header.status);
+    }
+
     #[test]
     fn test_binary_key_absent() {
         let mut client_stream = make_server_conn();
@@ -183,12 +570,13 @@ mod test {
                              extras_length: 0,
                              data_type: 0x00,
                              reserved: 0,
-                             total_body_length: 0,
+                             total_body_length: 1,
                              opaque: 0,
                              cas: 0,
                          },
                          extras: vec![],
                          key: vec!['_' as u8],
+                         value: vec![],
                      })
                      .unwrap();
         let response = client_stream.read_response().unwrap();
@@ -228,6 +616,7 @@ mod test {
                          },
                          extras: vec![],
                          key: vec![],
+                         value: vec![],
                      })
                      .unwrap();
         let response = client_stream.read_response().unwrap();
@@ -249,4 +638,122 @@ mod test {
                    },
                    response);
     }
+
+    /// A binary request with the given body, whose header describes it
+    fn binary_request(opcode: u8, extras: &[u8], key: &[u8], value: &[u8]) -> Request {
+        Request {
+            header: RequestHeader {
+                magic: constants::REQUEST_MAGIC,
+                opcode: opcode,
+                key_length: key.len() as u16,
+                extras_length: extras.len() as u8,
+                data_type: 0x00,
+                reserved: 0,
+                total_body_length: (extras.len() + key.len() + value.len()) as u32,
+                opaque: 0,
+                cas: 0,
+            },
+            extras: extras.to_vec(),
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_binary_framing() {
+        let mut client_stream = make_server_conn();
+        let get = binary_request(constants::opcodes::GET, &[], b"k", &[]);
+        // A write's value is read as part of it, so the next request is read correctly.
+        client_stream.write_request(&binary_request(constants::opcodes::SET,
+                                                    &[0, 0, 0, 0, 0, 0, 0, 0],
+                                                    b"k",
+                                                    b"value"))
+            .unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!(constants::response_status::NOT_SUPPORTED, response.header.status);
+        client_stream.write_request(&get).unwrap();
+        assert_eq!(b"v".to_vec(), client_stream.read_response().unwrap().value);
+        // A key longer than the body is invalid, as is the wrong magic, but neither loses track
+        // of where the next request starts.
+        let mut too_long = binary_request(constants::opcodes::GET, &[], b"kk", &[]);
+        too_long.header.key_length = 3;
+        let mut bad_magic = binary_request(constants::opcodes::GET, &[], b"k", &[]);
+        bad_magic.header.magic = constants::RESPONSE_MAGIC;
+        for request in &[too_long, bad_magic] {
+            client_stream.write_request(request).unwrap();
+            let response = client_stream.read_response().unwrap();
+            assert_eq!(constants::response_status::INVALID_ARGUMENTS, response.header.status);
+            client_stream.write_request(&get).unwrap();
+            assert_eq!(b"v".to_vec(), client_stream.read_response().unwrap().value);
+        }
+    }
+
+    #[test]
+    fn test_binary_gat_and_touch() {
+        let mut client_stream = make_server_conn();
+        let expiration = [0, 0, 0x0e, 0x10];
+        // Getting and touching is getting, quietly or not, with or without the key.
+        client_stream.write_request(&binary_request(constants::opcodes::GATK,
+                                                    &expiration,
+                                                    b"k",
+                                                    &[]))
+            .unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!((constants::opcodes::GATK, b"k".to_vec(), b"v".to_vec()),
+                   (response.header.opcode, response.key, response.value));
+        client_stream.write_request(&binary_request(constants::opcodes::GATQ,
+                                                    &expiration,
+                                                    b"_",
+                                                    &[]))
+            .unwrap();
+        client_stream.write_request(&binary_request(constants::opcodes::GAT,
+                                                    &expiration,
+                                                    b"_",
+                                                    &[]))
+            .unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!((constants::opcodes::GAT, constants::response_status::KEY_NOT_FOUND),
+                   (response.header.opcode, response.header.status));
+        // Touching finds the key or doesn't.
+        for &(key, status) in &[(b"k", constants::response_status::NO_ERROR),
+                                (b"_", constants::response_status::KEY_NOT_FOUND)] {
+            client_stream.write_request(&binary_request(constants::opcodes::TOUCH,
+                                                        &expiration,
+                                                        key,
+                                                        &[]))
+                .unwrap();
+            let response = client_stream.read_response().unwrap();
+            assert_eq!((constants::opcodes::TOUCH, status, 0),
+                       (response.header.opcode,
+                        response.header.status,
+                        response.header.total_body_length));
+        }
+        // Both need an expiration time.
+        client_stream.write_request(&binary_request(constants::opcodes::TOUCH, &[], b"k", &[]))
+            .unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!(constants::response_status::INVALID_ARGUMENTS, response.header.status);
+    }
+
+    #[test]
+    fn test_binary_version_and_verbosity() {
+        let mut client_stream = make_server_conn();
+        client_stream.write_request(&binary_request(constants::opcodes::VERSION, &[], &[], &[]))
+            .unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!(version().into_bytes(), response.value);
+        client_stream.write_request(&binary_request(constants::opcodes::VERBOSITY,
+                                                    &[0, 0, 0, 2],
+                                                    &[],
+                                                    &[]))
+            .unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!((constants::response_status::NO_ERROR, 0),
+                   (response.header.status, response.header.total_body_length));
+        // The verbosity needs a level.
+        client_stream.write_request(&binary_request(constants::opcodes::VERBOSITY, &[], &[], &[]))
+            .unwrap();
+        let response = client_stream.read_response().unwrap();
+        assert_eq!(constants::response_status::INVALID_ARGUMENTS, response.header.status);
+    }
 }
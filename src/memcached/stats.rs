@@ -0,0 +1,236 @@
+use std::env;
+use std::process;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use logging::LogLevel;
+use metrics::{Kind, Metrics, Sample};
+use metrics::connections::ByteCounts;
+
+use super::connections::{ConnectionLimit, Connections, OverLimit};
+
+/// Describes the database being served, as (name, value) stats
+pub type DbStats = Arc<Fn() -> Vec<(String, String)> + Send + Sync>;
+
+/// A memcached service's threads, and its connections if it has any
+struct Service {
+    threads: usize,
+    connections: Option<Arc<Connections>>,
+}
+
+/// Counters for the memcached services, shared by all of them and all their connections, so
+/// each answers stats requests for them all, as one memcached process would
+pub struct Stats {
+    started: Instant,
+    services: Mutex<Vec<Service>>,
+    db: DbStats,
+    metrics: Arc<Metrics>,
+    log_level: Arc<LogLevel>,
+    cmd_get: AtomicUsize,
+    get_hits: AtomicUsize,
+    get_misses: AtomicUsize,
+    bytes: Arc<ByteCounts>,
+}
+
+/// Our version and how we were built, as answered to version requests
+pub fn version() -> String {
+    format!("{} ({} build of {} for {}-{})",
+            env!("CARGO_PKG_VERSION"),
+            if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            },
+            env!("CDBD_GIT_REVISION"),
+            env::consts::ARCH,
+            env::consts::OS)
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Stats {
+    /// Stats for the services serving `db`, reported along with the rest of `metrics`
+    pub fn register(db: DbStats, metrics: Arc<Metrics>, log_level: Arc<LogLevel>) -> Arc<Stats> {
+        let stats = Arc::new(Stats {
+            started: Instant::now(),
+            services: Mutex::new(Vec::new()),
+            db: db,
+            metrics: metrics.clone(),
+            log_level: log_level,
+            cmd_get: AtomicUsize::new(0),
+            get_hits: AtomicUsize::new(0),
+            get_misses: AtomicUsize::new(0),
+            bytes: Arc::new(ByteCounts::new()),
+        });
+        let weak_stats: Weak<Stats> = Arc::downgrade(&stats);
+        metrics.add_collector(Box::new(move || {
+            weak_stats.upgrade().map_or(Vec::new(), |stats| stats.samples())
+        }));
+        stats
+    }
+
+    /// Count a service running on `threads` threads, with the connections it counts, if it
+    /// has connections.
+    pub fn add_service(&self, threads: usize, connections: Option<Arc<Connections>>) {
+        self.services.lock().unwrap().push(Service {
+            threads: threads,
+            connections: connections,
+        });
+    }
+
+    /// The threads all the services run on
+    fn threads(&self) -> usize {
+        self.services.lock().unwrap().iter().map(|service| service.threads).sum()
+    }
+
+    /// The sum of one of the services' connection counts
+    fn connections<F: Fn(&Connections) -> usize>(&self, count: F) -> usize {
+        self.services
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|service| service.connections.as_ref())
+            .map(|connections| count(connections))
+            .sum()
+    }
+
+    /// The most connections the services serve at once, if every service with connections is
+    /// limited, and what they do with more, if they all agree
+    fn limit(&self) -> Option<(usize, Option<OverLimit>)> {
+        let services = self.services.lock().unwrap();
+        let limits: Option<Vec<ConnectionLimit>> = services.iter()
+            .filter_map(|service| service.connections.as_ref())
+            .map(|connections| connections.limit())
+            .collect();
+        let limits = match limits {
+            Some(ref limits) if !limits.is_empty() => limits,
+            _ => return None,
+        };
+        let max = limits.iter().map(|limit| limit.max).sum();
+        let over_limit = limits[0].over_limit;
+        if limits.iter().all(|limit| limit.over_limit == over_limit) {
+            Some((max, Some(over_limit)))
+        } else {
+            Some((max, None))
+        }
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// The log level, which is shared by every service
+    pub fn log_level(&self) -> &LogLevel {
+        &self.log_level
+    }
+
+    /// Our connection and byte counts, as metrics samples, which are none until a service is
+    /// counted
+    fn samples(&self) -> Vec<Sample> {
+        if self.services.lock().unwrap().is_empty() {
+            return Vec::new();
+        }
+        let sample = |name, help, kind, value: usize| {
+            Sample {
+                name: name,
+                help: help,
+                kind: kind,
+                labels: vec![("protocol", "memcached".to_string())],
+                value: value as f64,
+            }
+        };
+        let mut samples = vec![sample("cdbd_connections",
+                                      "Open connections",
+                                      Kind::Gauge,
+                                      self.connections(Connections::current)),
+                               sample("cdbd_connections_total",
+                                      "Connections served",
+                                      Kind::Counter,
+                                      self.connections(Connections::total)),
+                               sample("cdbd_connections_rejected_total",
+                                      "Connections rejected for being over the limit",
+                                      Kind::Counter,
+                                      self.connections(Connections::rejected))];
+        samples.extend(self.bytes.samples("memcached"));
+        samples
+    }
+
+    /// Count a lookup of one key.
+    pub fn record_get(&self, hit: bool) {
+        self.cmd_get.fetch_add(1, Ordering::Relaxed);
+        if hit {
+            self.get_hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.get_misses.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// The bytes read from and written to clients, for counting them in
+    pub fn bytes(&self) -> Arc<ByteCounts> {
+        self.bytes.clone()
+    }
+
+    /// Count bytes read from and written to clients without a connection, as over UDP.
+    pub fn record_bytes(&self, read: usize, written: usize) {
+        self.bytes.record(read, written);
+    }
+
+    /// The stats in a group, as named by a stats request, or None if there's no such group.
+    ///
+    /// The general group is named "", and has memcached's usual stats plus cdbd's own "db_*"
+    /// stats about the database. The "settings" group describes how the service is configured,
+    /// and the "db" group has just the database stats.
+    pub fn group(&self, name: &str) -> Option<Vec<(String, String)>> {
+        match name {
+            "" => Some(self.general()),
+            "settings" => Some(self.settings()),
+            "db" => Some((self.db)()),
+            _ => None,
+        }
+    }
+
+    fn settings(&self) -> Vec<(String, String)> {
+        let mut settings = vec![("num_threads".to_string(), self.threads().to_string()),
+                                ("verbosity".to_string(),
+                                 self.log_level.verbosity().to_string())];
+        if let Some((max, over_limit)) = self.limit() {
+            settings.push(("maxconns".to_string(), max.to_string()));
+            if let Some(over_limit) = over_limit {
+                settings.push(("over_limit".to_string(),
+                               match over_limit {
+                                       OverLimit::Queue => "queue",
+                                       OverLimit::Reject => "reject",
+                                   }
+                                   .to_string()));
+            }
+        }
+        settings
+    }
+
+    fn general(&self) -> Vec<(String, String)> {
+        let counter = |c: &AtomicUsize| c.load(Ordering::Relaxed).to_string();
+        let mut stats = vec![("pid".to_string(), process::id().to_string()),
+                             ("uptime".to_string(), self.started.elapsed().as_secs().to_string()),
+                             ("time".to_string(), unix_time(SystemTime::now()).to_string()),
+                             ("version".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+                             ("curr_connections".to_string(),
+                              self.connections(Connections::current).to_string()),
+                             ("total_connections".to_string(),
+                              self.connections(Connections::total).to_string()),
+                             ("rejected_connections".to_string(),
+                              self.connections(Connections::rejected).to_string()),
+                             ("cmd_get".to_string(), counter(&self.cmd_get)),
+                             ("get_hits".to_string(), counter(&self.get_hits)),
+                             ("get_misses".to_string(), counter(&self.get_misses)),
+                             ("bytes_read".to_string(), self.bytes.read().to_string()),
+                             ("bytes_written".to_string(), self.bytes.written().to_string()),
+                             ("threads".to_string(), self.threads().to_string())];
+        stats.extend((self.db)());
+        stats
+    }
+}
+
@@ -1,5 +1,11 @@
 use std::io;
 use std::io::{BufRead, Write};
+use std::result;
+
+use base64;
+
+use kvstore::Value;
+use super::super::codec::WriteValue;
 use super::super::error::{Error, Result};
 
 /// A number of commands have the same arguments
@@ -18,6 +24,37 @@ pub struct IncrRequest {
     noreply: bool,
 }
 
+/// A flag on a meta command, as described under "Meta Commands" in protocol.txt. Those that ask
+/// for something are answered with the same letter, followed by what was asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MetaFlag {
+    /// b: the key is written in base64
+    Base64Key,
+    /// f: return the client flags
+    ClientFlags,
+    /// k: return the key
+    Key,
+    /// O: return this opaque token
+    Opaque(String),
+    /// q: don't answer a miss, so that a pipeline of gets can be ended with mn
+    Quiet,
+    /// s: return the value's size
+    Size,
+    /// t: return the remaining time to live, which is always -1, for never expiring
+    Ttl,
+    /// v: return the value
+    Value,
+}
+
+/// The key of a meta command
+#[derive(Debug)]
+pub struct MetaKey {
+    /// The key as written, which is how it's returned
+    pub token: String,
+    /// The key, decoded if it was written in base64
+    pub key: Vec<u8>,
+}
+
 // As defined at https://github.com/memcached/memcached/blob/master/doc/protocol.txt
 #[derive(Debug)]
 pub enum Request {
@@ -45,11 +82,39 @@ pub enum Request {
         exptime: u64,
         noreply: bool,
     },
+    /// Get and touch, as "gat" or, with `cas`, "gats"
+    Gat {
+        exptime: u64,
+        keys: Vec<String>,
+        cas: bool,
+    },
     Stats(String),
     FlushAll,
     Version,
+    /// Set how much is logged
+    Verbosity {
+        level: usize,
+        noreply: bool,
+    },
     Quit,
     Slabs(String),
+    /// A meta get, "mg"
+    MetaGet {
+        key: MetaKey,
+        flags: Vec<MetaFlag>,
+    },
+    /// A meta no-op, "mn", which is answered in turn, marking the end of a pipeline
+    MetaNoOp,
+    /// A meta debug request, "me", for what's known about a key
+    MetaDebug(MetaKey),
+    /// A meta set, "ms", whose data has been read
+    MetaSet,
+    /// A meta delete, "md"
+    MetaDelete,
+    /// A meta arithmetic request, "ma"
+    MetaArithmetic,
+    /// A known command given wrongly, with what's wrong with it
+    ClientError(&'static str),
     Error,
     Closed,
 }
@@ -59,7 +124,7 @@ pub enum Response<'a> {
     KeyValue {
         key: &'a str,
         flags: u16,
-        value: &'a [u8],
+        value: &'a Value,
         cas: Option<u64>,
     },
     End,
@@ -72,20 +137,42 @@ pub enum Response<'a> {
     NoReply,
     Ok,
     Stats(&'a [(&'a str, &'a str)]),
+    Version(&'a str),
+    /// A meta get's hit with its value, "VA"
+    MetaValue {
+        value: &'a Value,
+        flags: &'a [String],
+    },
+    /// A meta command's success without a value, "HD"
+    MetaHeader(&'a [String]),
+    /// A meta command's miss, "EN"
+    MetaNotFound(&'a [String]),
+    /// The answer to a meta no-op, "MN"
+    MetaNoOp,
+    /// What's known about a key, "ME"
+    MetaDebug {
+        key: &'a str,
+        info: &'a [(&'a str, String)],
+    },
 }
 
 fn get_keys(ks: &[&str]) -> Vec<String> {
     ks.iter().map(|k| k.to_string()).collect()
 }
 
-fn read_data_request(elts: &[&str], rdr: &mut BufRead) -> Result<DataRequest> {
-    if elts.len() != 5 {
-        return Err(Error::from("wrong number of args for data request"));
+/// How many bytes of data follow a command line, counting the "\r\n" after them, or None if the
+/// command has no data.
+pub fn data_length(line: &str) -> Option<usize> {
+    let elts: Vec<&str> = line.split_whitespace().collect();
+    match (elts.get(0).cloned(), elts.len()) {
+        (Some("set"), 5) | (Some("add"), 5) | (Some("replace"), 5) | (Some("append"), 5) |
+        (Some("prepend"), 5) | (Some("cas"), 6) => elts[4].parse::<usize>().ok().map(|n| n + 2),
+        (Some("ms"), n) if n >= 3 => elts[2].parse::<usize>().ok().map(|n| n + 2),
+        _ => None,
     }
-    let key = elts[1].to_string();
-    let flags = try!(elts[2].parse());
-    let exptime = try!(elts[3].parse());
-    let length = try!(elts[4].parse());
+}
+
+fn read_value(length: usize, rdr: &mut BufRead) -> Result<Vec<u8>> {
     let mut value: Vec<u8> = vec![0; length];
     try!(rdr.read_exact(value.as_mut_slice()));
     let mut endline = vec![0; 2];
@@ -94,11 +181,22 @@ fn read_data_request(elts: &[&str], rdr: &mut BufRead) -> Result<DataRequest> {
     if endline != "\r\n".as_bytes() {
         return Err(Error::from("missing newline at end of value"));
     }
+    Ok(value)
+}
+
+fn read_data_request(elts: &[&str], rdr: &mut BufRead) -> Result<DataRequest> {
+    if elts.len() != 5 {
+        return Err(Error::from("wrong number of args for data request"));
+    }
+    let key = elts[1].to_string();
+    let flags = try!(elts[2].parse());
+    let exptime = try!(elts[3].parse());
+    let length = try!(elts[4].parse());
     Ok(DataRequest {
         key: key,
         flags: flags,
         exptime: exptime,
-        value: value,
+        value: try!(read_value(length, rdr)),
     })
 }
 
@@ -108,14 +206,12 @@ fn read_cas(elts: &[&str], rdr: &mut BufRead) -> Result<Request> {
     let exptime = try!(elts[3].parse());
     let length = try!(elts[4].parse());
     let cas = try!(elts[5].parse());
-    let mut value: Vec<u8> = vec![0; length];
-    try!(rdr.read_exact(value.as_mut_slice()));
     Ok(Request::Cas {
         data: DataRequest {
             key: key,
             flags: flags,
             exptime: exptime,
-            value: value,
+            value: try!(read_value(length, rdr)),
         },
         cas: cas,
     })
@@ -129,6 +225,21 @@ fn parse_touch(elts: &[&str]) -> Result<Request> {
     })
 }
 
+fn parse_gat(elts: &[&str], cas: bool) -> Result<Request> {
+    Ok(Request::Gat {
+        exptime: try!(elts[1].parse()),
+        keys: get_keys(&elts[2..]),
+        cas: cas,
+    })
+}
+
+fn parse_verbosity(elts: &[&str]) -> Result<Request> {
+    Ok(Request::Verbosity {
+        level: try!(elts[1].parse()),
+        noreply: elts.get(2) == Some(&"noreply"),
+    })
+}
+
 fn parse_incr(elts: &[&str]) -> Result<IncrRequest> {
     Ok(IncrRequest {
         key: elts[1].to_string(),
@@ -137,7 +248,99 @@ fn parse_incr(elts: &[&str]) -> Result<IncrRequest> {
     })
 }
 
+fn parse_meta_flags(tokens: &[&str]) -> result::Result<Vec<MetaFlag>, &'static str> {
+    tokens.iter()
+        .map(|token| {
+            let flag = match token.chars().next() {
+                Some(flag) => flag,
+                None => return Err("invalid flag"),
+            };
+            match (flag, &token[flag.len_utf8()..]) {
+                ('b', "") => Ok(MetaFlag::Base64Key),
+                ('f', "") => Ok(MetaFlag::ClientFlags),
+                ('k', "") => Ok(MetaFlag::Key),
+                ('O', opaque) if !opaque.is_empty() => Ok(MetaFlag::Opaque(opaque.to_string())),
+                ('q', "") => Ok(MetaFlag::Quiet),
+                ('s', "") => Ok(MetaFlag::Size),
+                ('t', "") => Ok(MetaFlag::Ttl),
+                ('v', "") => Ok(MetaFlag::Value),
+                _ => Err("invalid flag"),
+            }
+        })
+        .collect()
+}
+
+fn parse_meta_key(token: &str, flags: &[MetaFlag]) -> result::Result<MetaKey, &'static str> {
+    let key = if flags.contains(&MetaFlag::Base64Key) {
+        try!(base64::decode(token).map_err(|_| "error decoding key"))
+    } else {
+        token.as_bytes().to_vec()
+    };
+    Ok(MetaKey {
+        token: token.to_string(),
+        key: key,
+    })
+}
+
+fn parse_meta_get(elts: &[&str]) -> result::Result<Request, &'static str> {
+    let flags = try!(parse_meta_flags(&elts[2..]));
+    Ok(Request::MetaGet {
+        key: try!(parse_meta_key(elts[1], &flags)),
+        flags: flags,
+    })
+}
+
+/// Read a meta set, whose data we have no use for.
+fn read_meta_set(elts: &[&str], rdr: &mut BufRead) -> Result<Request> {
+    let length = try!(elts[2].parse());
+    try!(read_value(length, rdr));
+    Ok(Request::MetaSet)
+}
+
+fn parse_meta_debug(elts: &[&str]) -> result::Result<Request, &'static str> {
+    let flags = try!(parse_meta_flags(&elts[2..]));
+    if flags.iter().any(|flag| *flag != MetaFlag::Base64Key) {
+        return Err("invalid flag");
+    }
+    Ok(Request::MetaDebug(try!(parse_meta_key(elts[1], &flags))))
+}
+
 impl Request {
+    /// The command's name, as reported in metrics
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Request::Get { cas: false, .. } => "get",
+            &Request::Get { cas: true, .. } => "gets",
+            &Request::Set(_) => "set",
+            &Request::Add(_) => "add",
+            &Request::Replace(_) => "replace",
+            &Request::Append(_) => "append",
+            &Request::Prepend(_) => "prepend",
+            &Request::Cas { .. } => "cas",
+            &Request::Delete { .. } => "delete",
+            &Request::Incr(_) => "incr",
+            &Request::Decr(_) => "decr",
+            &Request::Touch { .. } => "touch",
+            &Request::Gat { cas: false, .. } => "gat",
+            &Request::Gat { cas: true, .. } => "gats",
+            &Request::Stats(_) => "stats",
+            &Request::FlushAll => "flush_all",
+            &Request::Version => "version",
+            &Request::Verbosity { .. } => "verbosity",
+            &Request::Quit => "quit",
+            &Request::Slabs(_) => "slabs",
+            &Request::MetaGet { .. } => "mg",
+            &Request::MetaNoOp => "mn",
+            &Request::MetaDebug(_) => "me",
+            &Request::MetaSet => "ms",
+            &Request::MetaDelete => "md",
+            &Request::MetaArithmetic => "ma",
+            &Request::ClientError(_) => "invalid",
+            &Request::Error => "unknown",
+            &Request::Closed => "closed",
+        }
+    }
+
     pub fn parse(rdr: &mut BufRead) -> Request {
         let mut cmd = String::new();
         // There's surely some tidier way to write this mass of conditional matches.
@@ -169,6 +372,8 @@ impl Request {
                             ("prepend", 5) => read_data_request(&elts, rdr).map(Request::Prepend),
                             ("cas", 6) => read_cas(&elts, rdr),
                             ("touch", 3...4) => parse_touch(&elts),
+                            ("gat", n) if n >= 3 => parse_gat(&elts, false),
+                            ("gats", n) if n >= 3 => parse_gat(&elts, true),
                             ("delete", 2...3) => {
                                 Ok(Request::Delete {
                                     key: elts[1].to_string(),
@@ -181,7 +386,21 @@ impl Request {
                             ("stats", _) => Ok(Request::Stats(cmd.to_string())),
                             ("flush_all", 1) => Ok(Request::FlushAll),
                             ("version", 1) => Ok(Request::Version),
+                            ("verbosity", 2...3) => parse_verbosity(&elts),
                             ("quit", 1) => Ok(Request::Quit),
+                            ("mg", 1) | ("me", 1) | ("md", 1) | ("ma", 1) | ("ms", 1...2) => {
+                                Ok(Request::ClientError("bad command line format"))
+                            }
+                            ("mg", _) => {
+                                Ok(parse_meta_get(&elts).unwrap_or_else(Request::ClientError))
+                            }
+                            ("mn", 1) => Ok(Request::MetaNoOp),
+                            ("me", _) => {
+                                Ok(parse_meta_debug(&elts).unwrap_or_else(Request::ClientError))
+                            }
+                            ("ms", _) => read_meta_set(&elts, rdr),
+                            ("md", _) => Ok(Request::MetaDelete),
+                            ("ma", _) => Ok(Request::MetaArithmetic),
                             _ => Ok(Request::Error),
                         }
                         .unwrap_or(Request::Error)
@@ -192,15 +411,24 @@ impl Request {
     }
 }
 
+/// Write a meta response line: its code, then its flags.
+fn write_meta<W: Write + ?Sized>(wtr: &mut W, code: &str, flags: &[String]) -> io::Result<()> {
+    try!(write!(wtr, "{}", code));
+    for flag in flags.iter() {
+        try!(write!(wtr, " {}", flag));
+    }
+    write!(wtr, "\r\n")
+}
+
 impl<'a> Response<'a> {
-    pub fn write(&self, wtr: &mut Write) -> Result<()> {
+    pub fn write<W: WriteValue + ?Sized>(&self, wtr: &mut W) -> Result<()> {
         match self {
             &Response::KeyValue { key, flags, value, cas } => {
                 match cas {
                     None => write!(wtr, "VALUE {} {} {}\r\n", key, flags, value.len()),
                     Some(cas) => write!(wtr, "VALUE {} {} {} {}\r\n", key, flags, value.len(), cas),
                 }
-                .and_then(|_| wtr.write(value))
+                .and_then(|_| wtr.write_value(value))
                 .and_then(|_| write!(wtr, "\r\n"))
             }
             &Response::End => write!(wtr, "END\r\n"),
@@ -209,6 +437,7 @@ impl<'a> Response<'a> {
             &Response::NotFound => write!(wtr, "NOT_FOUND\r\n"),
             &Response::Touched => write!(wtr, "TOUCHED\r\n"),
             &Response::Ok => write!(wtr, "OK\r\n"),
+            &Response::Version(version) => write!(wtr, "VERSION {}\r\n", version),
             &Response::NoReply => Ok(()),
             &Response::ServerError(msg) => write!(wtr, "SERVER_ERROR {}\r\n", msg),
             &Response::ClientError(msg) => write!(wtr, "CLIENT_ERROR {}\r\n", msg),
@@ -218,6 +447,23 @@ impl<'a> Response<'a> {
                     .collect::<io::Result<Vec<()>>>()
                     .map(|_| ())
             }
+            &Response::MetaValue { value, flags } => {
+                write_meta(wtr, &format!("VA {}", value.len()), flags)
+                    .and_then(|_| wtr.write_value(value))
+                    .and_then(|_| write!(wtr, "\r\n"))
+            }
+            &Response::MetaHeader(flags) => write_meta(wtr, "HD", flags),
+            &Response::MetaNotFound(flags) => write_meta(wtr, "EN", flags),
+            &Response::MetaNoOp => write!(wtr, "MN\r\n"),
+            &Response::MetaDebug { key, info } => {
+                write!(wtr, "ME {}", key)
+                    .and_then(|_| {
+                        info.iter()
+                            .map(|&(name, ref value)| write!(wtr, " {}={}", name, value))
+                            .collect::<io::Result<Vec<()>>>()
+                    })
+                    .and_then(|_| write!(wtr, "\r\n"))
+            }
         }
         .map_err(Error::from)
     }
@@ -1,56 +1,210 @@
-use std::io::{BufRead, Write};
-
 use kvstore::KvStore;
+use metrics::Outcome;
 
-use super::protocol::{Request, Response};
+use super::protocol::{MetaFlag, MetaKey, Request, Response};
+use super::super::codec::WriteValue;
 use super::super::error::Result;
+use super::super::stats::{version, Stats};
+
+/// The protocol name to report in metrics
+const PROTOCOL: &'static str = "memcached";
 
-pub fn handle_client<KV: KvStore, T: BufRead, U: Write>(kvstore: KV,
-                                                        mut ins: T,
-                                                        mut outs: U)
-                                                        -> Result<()> {
-    trace!("memcached_text:connect");
-    loop {
-        match Request::parse(&mut ins) {
-            Request::Quit => {
-                break;
+/// Write the response to one request. Returns whether to keep the connection open.
+pub fn respond<KV: KvStore>(kvstore: &KV,
+                            stats: &Stats,
+                            request: &Request,
+                            outs: &mut WriteValue)
+                            -> Result<bool> {
+    let metrics = stats.metrics();
+    match request {
+        &Request::Quit => {
+            trace!("memcached_text:quit");
+            metrics.record(PROTOCOL, request.name(), Outcome::Ok);
+            return Ok(false);
+        }
+        &Request::Closed => {
+            return Ok(false);
+        }
+        &Request::Error => {
+            trace!("memcached_text:error");
+            metrics.record(PROTOCOL, request.name(), Outcome::Error);
+            try!(Response::Error.write(outs));
+        }
+        &Request::Get { ref keys, cas } => {
+            trace!("memcached_text:get {:?}", keys);
+            try!(write_values(kvstore, stats, request.name(), keys, cas, outs));
+        }
+        // Our data never expires, so getting and touching is just getting.
+        &Request::Gat { exptime, ref keys, cas } => {
+            trace!("memcached_text:gat {} {:?}", exptime, keys);
+            try!(write_values(kvstore, stats, request.name(), keys, cas, outs));
+        }
+        &Request::Touch { ref key, exptime, noreply } => {
+            let found = metrics.time_lookup(PROTOCOL, || kvstore.get(key.as_bytes())).is_some();
+            trace!("memcached_text:touch {:?} {} => {}",
+                   key,
+                   exptime,
+                   if found { "found" } else { "not found" });
+            metrics.record(PROTOCOL,
+                           request.name(),
+                           if found { Outcome::Hit } else { Outcome::Miss });
+            if noreply {
+                try!(Response::NoReply.write(outs));
+            } else if found {
+                try!(Response::Touched.write(outs));
+            } else {
+                try!(Response::NotFound.write(outs));
             }
-            Request::Closed => {
-                break;
+        }
+        &Request::Stats(ref cmd) => {
+            let group = cmd.split_whitespace().skip(1).collect::<Vec<&str>>().join(" ");
+            trace!("memcached_text:stats {:?}", group);
+            match stats.group(&group) {
+                Some(group) => {
+                    let group: Vec<(&str, &str)> =
+                        group.iter().map(|&(ref k, ref v)| (k.as_str(), v.as_str())).collect();
+                    try!(Response::Stats(&group).write(outs));
+                    try!(Response::End.write(outs));
+                    metrics.record(PROTOCOL, request.name(), Outcome::Ok);
+                }
+                None => {
+                    try!(Response::Error.write(outs));
+                    metrics.record(PROTOCOL, request.name(), Outcome::Error);
+                }
             }
-            Request::Error => {
-                trace!("memcached_text:error");
-                try!(Response::Error.write(&mut outs));
+        }
+        &Request::Version => {
+            trace!("memcached_text:version");
+            metrics.record(PROTOCOL, request.name(), Outcome::Ok);
+            try!(Response::Version(&version()).write(outs));
+        }
+        &Request::Verbosity { level, noreply } => {
+            trace!("memcached_text:verbosity {}", level);
+            stats.log_level().set_verbosity(level);
+            metrics.record(PROTOCOL, request.name(), Outcome::Ok);
+            if noreply {
+                try!(Response::NoReply.write(outs));
+            } else {
+                try!(Response::Ok.write(outs));
             }
-            Request::Get { keys, cas } => {
-                trace!("memcached_text:get {:?}", keys);
-                for key in keys.iter() {
-                    match kvstore.get(key.as_bytes()) {
-                        Some(value) => {
-                            try!(Response::KeyValue {
-                                     key: key,
-                                     flags: 0,
-                                     value: &value,
-                                     cas: if cas {
-                                         Some(0)
-                                     } else {
-                                         None
-                                     },
-                                 }
-                                 .write(&mut outs));
-                        }
-                        None => {}
+        }
+        &Request::ClientError(message) => {
+            trace!("memcached_text:client error {}", message);
+            metrics.record(PROTOCOL, request.name(), Outcome::Error);
+            try!(Response::ClientError(message).write(outs));
+        }
+        &Request::MetaGet { ref key, ref flags } => {
+            trace!("memcached_text:mg {:?} {:?}", key.token, flags);
+            let value = metrics.time_lookup(PROTOCOL, || kvstore.get(&key.key));
+            stats.record_get(value.is_some());
+            match value {
+                Some(value) => {
+                    metrics.record(PROTOCOL, request.name(), Outcome::Hit);
+                    let returned = meta_flags(key, flags, Some(value.len()));
+                    if flags.contains(&MetaFlag::Value) {
+                        try!(Response::MetaValue {
+                                 value: &value,
+                                 flags: &returned,
+                             }
+                             .write(outs));
+                    } else {
+                        try!(Response::MetaHeader(&returned).write(outs));
                     }
                 }
-                try!(Response::End.write(&mut outs));
+                None => {
+                    metrics.record(PROTOCOL, request.name(), Outcome::Miss);
+                    if !flags.contains(&MetaFlag::Quiet) {
+                        try!(Response::MetaNotFound(&meta_flags(key, flags, None)).write(outs));
+                    }
+                }
+            }
+        }
+        &Request::MetaNoOp => {
+            trace!("memcached_text:mn");
+            metrics.record(PROTOCOL, request.name(), Outcome::Ok);
+            try!(Response::MetaNoOp.write(outs));
+        }
+        &Request::MetaDebug(ref key) => {
+            trace!("memcached_text:me {:?}", key.token);
+            match metrics.time_lookup(PROTOCOL, || kvstore.get(&key.key)) {
+                Some(value) => {
+                    metrics.record(PROTOCOL, request.name(), Outcome::Hit);
+                    try!(Response::MetaDebug {
+                             key: &key.token,
+                             info: &[("exp", "-1".to_string()),
+                                     ("cas", "0".to_string()),
+                                     ("size", value.len().to_string())],
+                         }
+                         .write(outs));
+                }
+                None => {
+                    metrics.record(PROTOCOL, request.name(), Outcome::Miss);
+                    try!(Response::MetaNotFound(&[]).write(outs));
+                }
             }
-            op @ _ => {
-                trace!("memcached_text:not implemented method: {:?}", op);
-                try!(Response::ServerError("Read-only; method not implemented").write(&mut outs));
+        }
+        op @ _ => {
+            trace!("memcached_text:not implemented method: {:?}", op);
+            metrics.record(PROTOCOL, op.name(), Outcome::Unsupported);
+            try!(Response::ServerError("Read-only; method not implemented").write(outs));
+        }
+    }
+    Ok(true)
+}
+
+/// The flags to answer a meta get with, in the order they were asked for. Those about the value
+/// are left out of a miss, whose `size` is None.
+fn meta_flags(key: &MetaKey, flags: &[MetaFlag], size: Option<usize>) -> Vec<String> {
+    flags.iter()
+        .filter_map(|flag| match flag {
+            &MetaFlag::Key => Some(format!("k{}", key.token)),
+            // Say the returned key is in base64, as it was given.
+            &MetaFlag::Base64Key if flags.contains(&MetaFlag::Key) => Some("b".to_string()),
+            &MetaFlag::Opaque(ref opaque) => Some(format!("O{}", opaque)),
+            &MetaFlag::Size => size.map(|size| format!("s{}", size)),
+            &MetaFlag::ClientFlags => size.map(|_| "f0".to_string()),
+            &MetaFlag::Ttl => size.map(|_| "t-1".to_string()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Write the values of whichever of `keys` are found, as for a get.
+fn write_values<KV: KvStore>(kvstore: &KV,
+                             stats: &Stats,
+                             name: &'static str,
+                             keys: &[String],
+                             cas: bool,
+                             outs: &mut WriteValue)
+                             -> Result<()> {
+    let metrics = stats.metrics();
+    for key in keys.iter() {
+        let value = metrics.time_lookup(PROTOCOL, || kvstore.get(key.as_bytes()));
+        stats.record_get(value.is_some());
+        metrics.record(PROTOCOL,
+                       name,
+                       if value.is_some() {
+                           Outcome::Hit
+                       } else {
+                           Outcome::Miss
+                       });
+        match value {
+            Some(value) => {
+                try!(Response::KeyValue {
+                         key: key,
+                         flags: 0,
+                         value: &value,
+                         cas: if cas {
+                             Some(0)
+                         } else {
+                             None
+                         },
+                     }
+                     .write(outs));
             }
+            None => {}
         }
-        try!(outs.flush());
     }
-    trace!("memcached_text:disconnect");
+    try!(Response::End.write(outs));
     Ok(())
 }
@@ -0,0 +1,362 @@
+//! memcached over UDP, where every datagram starts with an 8-byte frame header: the request id,
+//! the datagram's sequence number, how many datagrams the message has, and 2 reserved bytes.
+
+use std::net::UdpSocket;
+use std::result;
+use std::sync::Arc;
+use std::thread;
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::BytesMut;
+use tokio::codec::Decoder;
+
+use kvstore::KvStore;
+use startup::StartupError;
+use super::codec::MemcachedCodec;
+use super::server::{respond, MemcachedOptions};
+use super::stats::Stats;
+use super::text::protocol::Response as TextResponse;
+
+/// The length of the frame header that starts every datagram
+const HEADER_LENGTH: usize = 8;
+
+/// The most bytes to send in one datagram, counting its header, as memcached does
+const MAX_DATAGRAM_LENGTH: usize = 1400;
+
+/// The longest datagram we can receive
+const MAX_REQUEST_LENGTH: usize = 65536;
+
+/// A datagram's frame header
+#[derive(Debug, PartialEq)]
+struct FrameHeader {
+    request_id: u16,
+    sequence: u16,
+    datagrams: u16,
+}
+
+impl FrameHeader {
+    /// The header at the start of `datagram`, or None if it's too short to have one
+    fn read(datagram: &[u8]) -> Option<FrameHeader> {
+        if datagram.len() < HEADER_LENGTH {
+            return None;
+        }
+        Some(FrameHeader {
+            request_id: BigEndian::read_u16(&datagram[0..2]),
+            sequence: BigEndian::read_u16(&datagram[2..4]),
+            datagrams: BigEndian::read_u16(&datagram[4..6]),
+        })
+    }
+
+    fn write(&self, datagram: &mut Vec<u8>) {
+        let mut header = [0; HEADER_LENGTH];
+        BigEndian::write_u16(&mut header[0..2], self.request_id);
+        BigEndian::write_u16(&mut header[2..4], self.sequence);
+        BigEndian::write_u16(&mut header[4..6], self.datagrams);
+        datagram.extend_from_slice(&header);
+    }
+}
+
+/// Serve memcached requests from UDP datagrams on `options.threads` threads, which this blocks
+/// to run, calling `bound` once it's listening.
+///
+/// There are no connections to drain, so this never returns once it's started.
+pub fn memcached_udp_server<KV>(kvstore: KV,
+                                host: &str,
+                                port: u16,
+                                options: &MemcachedOptions,
+                                stats: Arc<Stats>,
+                                bound: &Fn())
+                                -> result::Result<(), StartupError>
+    where KV: KvStore + Clone + Send + Sync + 'static
+{
+    let socket = try!(UdpSocket::bind((host, port))
+        .map_err(|e| StartupError::bind("memcached UDP", host, port, e)));
+    bound();
+    stats.add_service(options.threads, None);
+    let mut workers = Vec::new();
+    for i in 0..options.threads {
+        let socket = try!(socket.try_clone()
+            .map_err(|e| StartupError::setup("share the memcached UDP socket", e)));
+        let kvstore = kvstore.clone();
+        let stats = stats.clone();
+        workers.push(try!(thread::Builder::new()
+            .name(format!("memcached-udp-{}", i))
+            .spawn(move || serve(&kvstore, &stats, &socket))
+            .map_err(|e| StartupError::setup("start memcached UDP threads", e))));
+    }
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(())
+}
+
+/// Answer the requests in each datagram received on `socket`, forever.
+fn serve<KV: KvStore>(kvstore: &KV, stats: &Stats, socket: &UdpSocket) {
+    let mut buf = vec![0; MAX_REQUEST_LENGTH];
+    loop {
+        let (length, addr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("memcached UDP receive failed: {}", e);
+                continue;
+            }
+        };
+        stats.record_bytes(length, 0);
+        for datagram in answer(kvstore, stats, &buf[..length]) {
+            match socket.send_to(&datagram, addr) {
+                Ok(sent) => stats.record_bytes(0, sent),
+                Err(e) => {
+                    trace!("memcached UDP send to {} failed: {}", addr, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// The datagrams answering the requests in one datagram, which are none if it isn't framed or
+/// its requests are all quiet.
+fn answer<KV: KvStore>(kvstore: &KV, stats: &Stats, datagram: &[u8]) -> Vec<Vec<u8>> {
+    let header = match FrameHeader::read(datagram) {
+        Some(header) => header,
+        None => {
+            trace!("memcached UDP datagram too short for a frame header");
+            return Vec::new();
+        }
+    };
+    let mut response = Vec::new();
+    if header.sequence != 0 || header.datagrams != 1 {
+        // Like memcached, we only take requests that fit in one datagram.
+        trace!("memcached UDP request {} in {} datagrams",
+               header.request_id,
+               header.datagrams);
+        let _ = TextResponse::ServerError("multi-packet request not supported")
+            .write(&mut response);
+        return frames(header.request_id, &response);
+    }
+    let mut codec = MemcachedCodec::new();
+    let mut buf = BytesMut::from(&datagram[HEADER_LENGTH..]);
+    loop {
+        let request = match codec.decode_eof(&mut buf) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(e) => {
+                trace!("memcached UDP request {} is invalid: {}", header.request_id, e);
+                break;
+            }
+        };
+        match respond(kvstore, stats, &request, &mut response) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                trace!("memcached UDP error answering request {}: {:?}",
+                       header.request_id,
+                       e);
+                break;
+            }
+        }
+    }
+    frames(header.request_id, &response)
+}
+
+/// Split a response into datagrams, each with its frame header.
+fn frames(request_id: u16, response: &[u8]) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = response.chunks(MAX_DATAGRAM_LENGTH - HEADER_LENGTH).collect();
+    if chunks.len() > u16::max_value() as usize {
+        warn!("memcached UDP response to request {} is too long to send ({} bytes)",
+              request_id,
+              response.len());
+        return Vec::new();
+    }
+    chunks.iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut datagram = Vec::with_capacity(HEADER_LENGTH + chunk.len());
+            FrameHeader {
+                    request_id: request_id,
+                    sequence: i as u16,
+                    datagrams: chunks.len() as u16,
+                }
+                .write(&mut datagram);
+            datagram.extend_from_slice(chunk);
+            datagram
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use kvstore::{KvStore, Value};
+    use logging::LogLevel;
+    use metrics::Metrics;
+    use super::{FrameHeader, HEADER_LENGTH, MAX_DATAGRAM_LENGTH};
+    use super::super::binary::protocol::{constants, PWrite, Request, RequestHeader};
+    use super::super::connections::Connections;
+    use super::super::stats::Stats;
+
+    /// The length of the value at "big", which takes several datagrams to send
+    const BIG_LENGTH: usize = 5000;
+
+    /// A KvStore with {"k": "v"} and a value at "big" too long for one datagram
+    #[derive(Clone)]
+    struct DummyKvStore {
+    }
+
+    impl KvStore for DummyKvStore {
+        fn get(&self, key: &[u8]) -> Option<Value> {
+            match key {
+                b"k" => Some(Value::from("v")),
+                b"big" => Some(Value::from(vec![b'x'; BIG_LENGTH])),
+                _ => None,
+            }
+        }
+    }
+
+    /// Start a server, returning a socket connected to it.
+    fn make_server_socket() -> UdpSocket {
+        make_server_socket_with(Stats::register(Arc::new(Vec::new),
+                                                Arc::new(Metrics::new()),
+                                                Arc::new(LogLevel::detached(0))))
+    }
+
+    /// Start a server counting in `stats`, returning a socket connected to it.
+    fn make_server_socket_with(stats: Arc<Stats>) -> UdpSocket {
+        let server = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.local_addr().unwrap();
+        stats.add_service(1, None);
+        thread::spawn(move || super::serve(&DummyKvStore {}, &stats, &server));
+        let client = UdpSocket::bind(("127.0.0.1", 0)).unwrap();
+        client.connect(addr).unwrap();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        client
+    }
+
+    /// Send `payload` as request `request_id`, in one datagram.
+    fn send(socket: &UdpSocket, request_id: u16, payload: &[u8]) {
+        let mut datagram = Vec::new();
+        FrameHeader {
+                request_id: request_id,
+                sequence: 0,
+                datagrams: 1,
+            }
+            .write(&mut datagram);
+        datagram.extend_from_slice(payload);
+        socket.send(&datagram).unwrap();
+    }
+
+    /// Receive the datagrams of a response, checking their headers, and join their payloads.
+    fn receive(socket: &UdpSocket, request_id: u16) -> Vec<u8> {
+        let mut response = Vec::new();
+        let mut buf = [0; MAX_DATAGRAM_LENGTH + 1];
+        let mut sequence = 0;
+        loop {
+            let length = socket.recv(&mut buf).unwrap();
+            assert!(length <= MAX_DATAGRAM_LENGTH);
+            let header = FrameHeader::read(&buf[..length]).unwrap();
+            assert_eq!((request_id, sequence), (header.request_id, header.sequence));
+            response.extend_from_slice(&buf[HEADER_LENGTH..length]);
+            sequence += 1;
+            if sequence == header.datagrams {
+                return response;
+            }
+        }
+    }
+
+    #[test]
+    fn test_text_get() {
+        let socket = make_server_socket();
+        send(&socket, 7, b"get k _\r\ngets k\r\n");
+        assert_eq!(b"VALUE k 0 1\r\nv\r\nEND\r\nVALUE k 0 1 0\r\nv\r\nEND\r\n".to_vec(),
+                   receive(&socket, 7));
+    }
+
+    #[test]
+    fn test_split_response() {
+        let socket = make_server_socket();
+        send(&socket, 0xabcd, b"get big\r\n");
+        let mut expected = format!("VALUE big 0 {}\r\n", BIG_LENGTH).into_bytes();
+        expected.extend_from_slice(&[b'x'; BIG_LENGTH]);
+        expected.extend_from_slice(b"\r\nEND\r\n");
+        assert_eq!(expected, receive(&socket, 0xabcd));
+    }
+
+    #[test]
+    fn test_binary_get() {
+        let socket = make_server_socket();
+        let mut payload = Vec::new();
+        payload.write_request(&Request {
+                header: RequestHeader {
+                    magic: constants::REQUEST_MAGIC,
+                    opcode: constants::opcodes::GETK,
+                    key_length: 1,
+                    extras_length: 0,
+                    data_type: 0x00,
+                    reserved: 0,
+                    total_body_length: 1,
+                    opaque: 3,
+                    cas: 0,
+                },
+                extras: vec![],
+                key: b"k".to_vec(),
+                value: vec![],
+            })
+            .unwrap();
+        send(&socket, 1, &payload);
+        let response = receive(&socket, 1);
+        // The response header, 4 bytes of flags, the key and the value
+        assert_eq!((constants::RESPONSE_MAGIC, constants::opcodes::GETK),
+                   (response[0], response[1]));
+        assert_eq!(b"kv".to_vec(), response[28..].to_vec());
+    }
+
+    #[test]
+    fn test_shared_stats() {
+        let metrics = Arc::new(Metrics::new());
+        let stats = Stats::register(Arc::new(Vec::new),
+                                    metrics.clone(),
+                                    Arc::new(LogLevel::detached(0)));
+        // A TCP service with one connection open shares the stats.
+        let connections = Arc::new(Connections::new(None));
+        stats.add_service(2, Some(connections.clone()));
+        let _open = Connections::open(&connections).unwrap();
+        let socket = make_server_socket_with(stats);
+        send(&socket, 1, b"get k\r\n");
+        receive(&socket, 1);
+        send(&socket, 2, b"stats\r\n");
+        let response = String::from_utf8(receive(&socket, 2)).unwrap();
+        for stat in &["STAT curr_connections 1\r\n",
+                      "STAT cmd_get 1\r\n",
+                      "STAT get_hits 1\r\n",
+                      "STAT bytes_read 30\r\n",
+                      "STAT bytes_written 29\r\n",
+                      "STAT threads 3\r\n"] {
+            assert!(response.contains(stat), "{:?} missing from {:?}", stat, response);
+        }
+        let rendered = metrics.render();
+        for line in &["cdbd_connections{protocol=\"memcached\"} 1\n",
+                      "cdbd_read_bytes_total{protocol=\"memcached\"} 30\n"] {
+            assert!(rendered.contains(line), "{:?} missing from {}", line, rendered);
+        }
+    }
+
+    #[test]
+    fn test_multi_datagram_request() {
+        let socket = make_server_socket();
+        let mut datagram = Vec::new();
+        FrameHeader {
+                request_id: 2,
+                sequence: 0,
+                datagrams: 2,
+            }
+            .write(&mut datagram);
+        datagram.extend_from_slice(b"get k\r\n");
+        socket.send(&datagram).unwrap();
+        assert_eq!(b"SERVER_ERROR multi-packet request not supported\r\n".to_vec(),
+                   receive(&socket, 2));
+    }
+}
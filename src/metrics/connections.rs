@@ -0,0 +1,199 @@
+//! Byte counts for every service, and connection counts for services that keep no stats of their
+//! own, like Redis and HTTP
+
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::Poll;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{Kind, Metrics, Sample};
+
+/// The bytes a service has read from and written to its clients
+pub struct ByteCounts {
+    read: AtomicUsize,
+    written: AtomicUsize,
+}
+
+impl ByteCounts {
+    pub fn new() -> ByteCounts {
+        ByteCounts {
+            read: AtomicUsize::new(0),
+            written: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count bytes read and written outside a Counted stream, as over UDP.
+    pub fn record(&self, read: usize, written: usize) {
+        self.read.fetch_add(read, Ordering::Relaxed);
+        self.written.fetch_add(written, Ordering::Relaxed);
+    }
+
+    pub fn read(&self) -> usize {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    pub fn written(&self) -> usize {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Our counts as metrics samples, labeled with the service's `protocol`
+    pub fn samples(&self, protocol: &str) -> Vec<Sample> {
+        vec![Sample {
+                 name: "cdbd_read_bytes_total",
+                 help: "Bytes read from clients",
+                 kind: Kind::Counter,
+                 labels: vec![("protocol", protocol.to_string())],
+                 value: self.read() as f64,
+             },
+             Sample {
+                 name: "cdbd_written_bytes_total",
+                 help: "Bytes written to clients",
+                 kind: Kind::Counter,
+                 labels: vec![("protocol", protocol.to_string())],
+                 value: self.written() as f64,
+             }]
+    }
+}
+
+/// A service's connection and byte counts
+pub struct ConnectionMetrics {
+    protocol: &'static str,
+    current: AtomicUsize,
+    total: AtomicUsize,
+    bytes: Arc<ByteCounts>,
+}
+
+/// A connection being served, which counts as open until this is dropped
+pub struct OpenConnection {
+    connections: Arc<ConnectionMetrics>,
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.connections.current.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionMetrics {
+    /// Counts for a service speaking `protocol`, reported along with the rest of `metrics`
+    pub fn register(protocol: &'static str, metrics: &Metrics) -> Arc<ConnectionMetrics> {
+        let connections = Arc::new(ConnectionMetrics {
+            protocol: protocol,
+            current: AtomicUsize::new(0),
+            total: AtomicUsize::new(0),
+            bytes: Arc::new(ByteCounts::new()),
+        });
+        let reported = connections.clone();
+        metrics.add_collector(Box::new(move || reported.samples()));
+        connections
+    }
+
+    /// Count a newly accepted connection.
+    pub fn open(connections: &Arc<ConnectionMetrics>) -> OpenConnection {
+        connections.current.fetch_add(1, Ordering::Relaxed);
+        connections.total.fetch_add(1, Ordering::Relaxed);
+        OpenConnection { connections: connections.clone() }
+    }
+
+    /// The bytes read from and written to our connections, for counting them in
+    pub fn bytes(&self) -> Arc<ByteCounts> {
+        self.bytes.clone()
+    }
+
+    /// Our counts, as metrics samples
+    fn samples(&self) -> Vec<Sample> {
+        let sample = |name, help, kind, counter: &AtomicUsize| {
+            Sample {
+                name: name,
+                help: help,
+                kind: kind,
+                labels: vec![("protocol", self.protocol.to_string())],
+                value: counter.load(Ordering::Relaxed) as f64,
+            }
+        };
+        let mut samples = vec![sample("cdbd_connections",
+                                      "Open connections",
+                                      Kind::Gauge,
+                                      &self.current),
+                               sample("cdbd_connections_total",
+                                      "Connections served",
+                                      Kind::Counter,
+                                      &self.total)];
+        samples.extend(self.bytes.samples(self.protocol));
+        samples
+    }
+}
+
+/// A stream that counts the bytes read from and written to it
+pub struct Counted<S> {
+    inner: S,
+    bytes: Arc<ByteCounts>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, bytes: Arc<ByteCounts>) -> Counted<S> {
+        Counted {
+            inner: inner,
+            bytes: bytes,
+        }
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.bytes.read.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.bytes.written.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Counted<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Counted<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, Read, Write};
+
+    use metrics::Metrics;
+    use super::{ConnectionMetrics, Counted};
+
+    #[test]
+    fn test_counts() {
+        let metrics = Metrics::new();
+        let connections = ConnectionMetrics::register("redis", &metrics);
+        let open = ConnectionMetrics::open(&connections);
+        drop(ConnectionMetrics::open(&connections));
+        let mut ins = Counted::new(Cursor::new(b"PING\r\n".to_vec()), connections.bytes());
+        ins.read_to_end(&mut Vec::new()).unwrap();
+        let mut outs = Counted::new(Vec::new(), connections.bytes());
+        outs.write_all(b"+PONG\r\n").unwrap();
+        let rendered = metrics.render();
+        for line in &["cdbd_connections{protocol=\"redis\"} 1\n",
+                      "cdbd_connections_total{protocol=\"redis\"} 2\n",
+                      "cdbd_read_bytes_total{protocol=\"redis\"} 6\n",
+                      "cdbd_written_bytes_total{protocol=\"redis\"} 7\n"] {
+            assert!(rendered.contains(line), "{:?} missing from {}", line, rendered);
+        }
+        drop(open);
+        assert!(metrics.render().contains("cdbd_connections{protocol=\"redis\"} 0\n"));
+    }
+}
@@ -0,0 +1,294 @@
+//! Metrics in the Prometheus text exposition format, as described at
+//! https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The upper bounds, in seconds, of the lookup latency histogram buckets
+const LOOKUP_BUCKETS: &'static [f64] = &[0.00001, 0.000025, 0.00005, 0.0001, 0.00025, 0.0005,
+                                         0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25,
+                                         0.5, 1.0];
+
+/// How a request turned out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Outcome {
+    /// A lookup that found its key
+    Hit,
+    /// A lookup that didn't find its key
+    Miss,
+    /// A request that was answered, but isn't a lookup
+    Ok,
+    /// A malformed request
+    Error,
+    /// A request for something we don't do
+    Unsupported,
+}
+
+impl Outcome {
+    fn name(&self) -> &'static str {
+        match *self {
+            Outcome::Hit => "hit",
+            Outcome::Miss => "miss",
+            Outcome::Ok => "ok",
+            Outcome::Error => "error",
+            Outcome::Unsupported => "unsupported",
+        }
+    }
+}
+
+/// Whether a sample's value only goes up, or can go up and down
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match *self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        }
+    }
+}
+
+/// One value of a metric, as reported by a collector
+pub struct Sample {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+/// Reports samples of metrics kept elsewhere, such as a server's connection counts
+pub type Collector = Box<Fn() -> Vec<Sample> + Send + Sync>;
+
+/// A histogram of durations
+struct Histogram {
+    /// How many observations fell in each bucket, not counting the earlier buckets
+    buckets: Vec<AtomicUsize>,
+    count: AtomicUsize,
+    sum_nanos: AtomicUsize,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: LOOKUP_BUCKETS.iter().map(|_| AtomicUsize::new(0)).collect(),
+            count: AtomicUsize::new(0),
+            sum_nanos: AtomicUsize::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs() as f64 + duration.subsec_nanos() as f64 / 1e9;
+        if let Some(i) = LOOKUP_BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let nanos = duration.as_secs() as usize * 1000000000 + duration.subsec_nanos() as usize;
+        self.sum_nanos.fetch_add(nanos, Ordering::Relaxed);
+    }
+}
+
+/// A process's metrics
+pub struct Metrics {
+    /// Requests counted by protocol, command and outcome
+    requests: RwLock<BTreeMap<(&'static str, &'static str, Outcome), AtomicUsize>>,
+    /// Lookup latencies by protocol
+    lookups: RwLock<BTreeMap<&'static str, Histogram>>,
+    collectors: Mutex<Vec<Collector>>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            requests: RwLock::new(BTreeMap::new()),
+            lookups: RwLock::new(BTreeMap::new()),
+            collectors: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Count a request.
+    pub fn record(&self, protocol: &'static str, command: &'static str, outcome: Outcome) {
+        let key = (protocol, command, outcome);
+        if let Some(count) = self.requests.read().unwrap().get(&key) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.requests
+            .write()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| AtomicUsize::new(0))
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Run a lookup, recording how long it took.
+    pub fn time_lookup<T, F: FnOnce() -> T>(&self, protocol: &'static str, lookup: F) -> T {
+        let start = Instant::now();
+        let result = lookup();
+        let duration = start.elapsed();
+        if let Some(histogram) = self.lookups.read().unwrap().get(protocol) {
+            histogram.observe(duration);
+            return result;
+        }
+        self.lookups
+            .write()
+            .unwrap()
+            .entry(protocol)
+            .or_insert_with(Histogram::new)
+            .observe(duration);
+        result
+    }
+
+    /// Report the samples from `collector` along with our own.
+    pub fn add_collector(&self, collector: Collector) {
+        self.collectors.lock().unwrap().push(collector);
+    }
+
+    /// Write all metrics in the text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP cdbd_requests_total Requests answered, by protocol, command and \
+                      outcome\n");
+        out.push_str("# TYPE cdbd_requests_total counter\n");
+        for (&(protocol, command, outcome), count) in self.requests.read().unwrap().iter() {
+            writeln!(out,
+                     "cdbd_requests_total{{protocol=\"{}\",command=\"{}\",outcome=\"{}\"}} {}",
+                     protocol,
+                     command,
+                     outcome.name(),
+                     count.load(Ordering::Relaxed))
+                .unwrap();
+        }
+        out.push_str("# HELP cdbd_lookup_duration_seconds How long database lookups took, by \
+                      protocol\n");
+        out.push_str("# TYPE cdbd_lookup_duration_seconds histogram\n");
+        for (protocol, histogram) in self.lookups.read().unwrap().iter() {
+            let mut cumulative = 0;
+            for (bound, count) in LOOKUP_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                cumulative += count.load(Ordering::Relaxed);
+                writeln!(out,
+                         "cdbd_lookup_duration_seconds_bucket{{protocol=\"{}\",le=\"{}\"}} {}",
+                         protocol,
+                         bound,
+                         cumulative)
+                    .unwrap();
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            writeln!(out,
+                     "cdbd_lookup_duration_seconds_bucket{{protocol=\"{}\",le=\"+Inf\"}} {}",
+                     protocol,
+                     count)
+                .unwrap();
+            writeln!(out,
+                     "cdbd_lookup_duration_seconds_sum{{protocol=\"{}\"}} {}",
+                     protocol,
+                     histogram.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9)
+                .unwrap();
+            writeln!(out,
+                     "cdbd_lookup_duration_seconds_count{{protocol=\"{}\"}} {}",
+                     protocol,
+                     count)
+                .unwrap();
+        }
+        // Group the collected samples into families, each written with its help and type once.
+        let mut families: Vec<(&'static str, &'static str, Kind, Vec<Sample>)> = Vec::new();
+        for collector in self.collectors.lock().unwrap().iter() {
+            for sample in collector() {
+                match families.iter().position(|f| f.0 == sample.name) {
+                    Some(i) => families[i].3.push(sample),
+                    None => families.push((sample.name, sample.help, sample.kind, vec![sample])),
+                }
+            }
+        }
+        for (name, help, kind, samples) in families {
+            writeln!(out, "# HELP {} {}", name, help).unwrap();
+            writeln!(out, "# TYPE {} {}", name, kind.name()).unwrap();
+            for sample in samples {
+                let labels: Vec<String> = sample.labels
+                    .iter()
+                    .map(|&(label, ref value)| format!("{}=\"{}\"", label, escape(value)))
+                    .collect();
+                if labels.is_empty() {
+                    writeln!(out, "{} {}", name, sample.value).unwrap();
+                } else {
+                    writeln!(out, "{}{{{}}} {}", name, labels.join(","), sample.value).unwrap();
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Escape a label value.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+pub mod connections;
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use super::{Histogram, Kind, Metrics, Outcome, Sample};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.record("memcached", "get", Outcome::Hit);
+        metrics.record("memcached", "get", Outcome::Hit);
+        metrics.record("memcached", "get", Outcome::Miss);
+        assert_eq!(Some(1), metrics.time_lookup("memcached", || Some(1)));
+        metrics.add_collector(Box::new(|| {
+            vec![Sample {
+                     name: "cdbd_connections",
+                     help: "Open connections",
+                     kind: Kind::Gauge,
+                     labels: vec![("protocol", "memcached".to_string())],
+                     value: 3.0,
+                 },
+                 Sample {
+                     name: "cdbd_db_generation",
+                     help: "Database generation",
+                     kind: Kind::Gauge,
+                     labels: vec![],
+                     value: 1.0,
+                 }]
+        }));
+        let rendered = metrics.render();
+        for line in &["# TYPE cdbd_requests_total counter\n",
+                      "cdbd_requests_total{protocol=\"memcached\",command=\"get\",\
+                       outcome=\"hit\"} 2\n",
+                      "cdbd_requests_total{protocol=\"memcached\",command=\"get\",\
+                       outcome=\"miss\"} 1\n",
+                      "# TYPE cdbd_lookup_duration_seconds histogram\n",
+                      "cdbd_lookup_duration_seconds_bucket{protocol=\"memcached\",\
+                       le=\"+Inf\"} 1\n",
+                      "cdbd_lookup_duration_seconds_count{protocol=\"memcached\"} 1\n",
+                      "# HELP cdbd_connections Open connections\n# TYPE cdbd_connections \
+                       gauge\ncdbd_connections{protocol=\"memcached\"} 3\n",
+                      "cdbd_db_generation 1\n"] {
+            assert!(rendered.contains(line), "{:?} missing from {}", line, rendered);
+        }
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new();
+        histogram.observe(Duration::new(0, 3000));
+        histogram.observe(Duration::new(0, 3000000));
+        histogram.observe(Duration::new(2, 0));
+        let counts: Vec<usize> =
+            histogram.buckets.iter().map(|c| c.load(Ordering::Relaxed)).collect();
+        assert_eq!(vec![1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0], counts);
+        assert_eq!(3, histogram.count.load(Ordering::Relaxed));
+    }
+}
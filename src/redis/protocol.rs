@@ -0,0 +1,117 @@
+//! As described at https://redis.io/topics/protocol
+
+use std::io::{BufRead, Result, Write};
+
+/// A Redis request
+#[derive(Debug, PartialEq, Eq)]
+pub enum Request {
+    /// A command name and its arguments, from either a RESP array or an inline command
+    Command(Vec<Vec<u8>>),
+    /// An empty inline command, which Redis silently ignores
+    Empty,
+    /// A malformed request; the connection can't be trusted to be in sync afterward
+    ProtocolError(String),
+    Closed,
+}
+
+pub enum Response<'a> {
+    Status(&'a str),
+    Error(&'a str),
+    Integer(i64),
+    Bulk(Option<&'a [u8]>),
+    /// The header of an array; its elements must be written after it
+    ArrayHeader(usize),
+}
+
+/// Read one line, stripping the trailing "\r\n" (or bare "\n").
+fn read_line(rdr: &mut BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    match try!(rdr.read_until(b'\n', &mut line)) {
+        0 => Ok(None),
+        _ => {
+            if line.ends_with(b"\n") {
+                line.pop();
+                if line.ends_with(b"\r") {
+                    line.pop();
+                }
+            }
+            Ok(Some(line))
+        }
+    }
+}
+
+/// Parse the integer following a RESP type byte, as in "*3" or "$5".
+fn parse_length(line: &[u8]) -> Option<i64> {
+    String::from_utf8_lossy(&line[1..]).parse().ok()
+}
+
+fn read_array(header: &[u8], rdr: &mut BufRead) -> Result<Request> {
+    let count = match parse_length(header) {
+        Some(n) if n <= 0 => return Ok(Request::Empty),
+        Some(n) if n <= 1024 * 1024 => n as usize,
+        _ => return Ok(Request::ProtocolError("invalid multibulk length".to_string())),
+    };
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = match try!(read_line(rdr)) {
+            Some(line) => line,
+            None => return Ok(Request::Closed),
+        };
+        if line.first() != Some(&b'$') {
+            return Ok(Request::ProtocolError("expected '$'".to_string()));
+        }
+        let length = match parse_length(&line) {
+            Some(n) if n >= 0 && n <= 512 * 1024 * 1024 => n as usize,
+            _ => return Ok(Request::ProtocolError("invalid bulk length".to_string())),
+        };
+        let mut arg = vec![0; length + 2];
+        try!(rdr.read_exact(&mut arg));
+        if !arg.ends_with(b"\r\n") {
+            return Ok(Request::ProtocolError("missing CRLF after bulk string".to_string()));
+        }
+        arg.truncate(length);
+        args.push(arg);
+    }
+    Ok(Request::Command(args))
+}
+
+impl Request {
+    pub fn parse(rdr: &mut BufRead) -> Request {
+        let line = match read_line(rdr) {
+            Err(_) => return Request::Closed,
+            Ok(None) => return Request::Closed,
+            Ok(Some(line)) => line,
+        };
+        match line.first() {
+            Some(&b'*') => read_array(&line, rdr).unwrap_or(Request::Closed),
+            _ => {
+                // An inline command, as typed into telnet
+                let args: Vec<Vec<u8>> = line.split(|c| c.is_ascii_whitespace())
+                    .filter(|arg| !arg.is_empty())
+                    .map(|arg| arg.to_vec())
+                    .collect();
+                match args.len() {
+                    0 => Request::Empty,
+                    _ => Request::Command(args),
+                }
+            }
+        }
+    }
+}
+
+impl<'a> Response<'a> {
+    pub fn write(&self, wtr: &mut Write) -> Result<()> {
+        match self {
+            &Response::Status(msg) => write!(wtr, "+{}\r\n", msg),
+            &Response::Error(msg) => write!(wtr, "-{}\r\n", msg),
+            &Response::Integer(n) => write!(wtr, ":{}\r\n", n),
+            &Response::Bulk(None) => write!(wtr, "$-1\r\n"),
+            &Response::Bulk(Some(value)) => {
+                write!(wtr, "${}\r\n", value.len())
+                    .and_then(|_| wtr.write_all(value))
+                    .and_then(|_| write!(wtr, "\r\n"))
+            }
+            &Response::ArrayHeader(n) => write!(wtr, "*{}\r\n", n),
+        }
+    }
+}
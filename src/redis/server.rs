@@ -0,0 +1,370 @@
+use std::io::{BufRead, BufReader, BufWriter, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::result;
+use std::sync::Arc;
+use std::thread;
+
+use kvstore::{KvStore, Value};
+use metrics::{Metrics, Outcome};
+use metrics::connections::{ConnectionMetrics, Counted};
+use startup::StartupError;
+use super::protocol::{Request, Response};
+
+/// The protocol name to report in metrics
+const PROTOCOL: &'static str = "redis";
+
+/// Commands we answer, with their arity (negative meaning "at least") as reported by `COMMAND`
+const COMMANDS: &'static [(&'static str, i64)] = &[("get", 2),
+                                                   ("mget", -2),
+                                                   ("exists", -2),
+                                                   ("strlen", 2),
+                                                   ("ping", -1),
+                                                   ("echo", 2),
+                                                   ("quit", 1),
+                                                   ("command", -1)];
+
+/// Commands that would modify the database, which we reject as read-only
+const WRITE_COMMANDS: &'static [&'static str] = &["append", "decr", "decrby", "del", "expire",
+                                                  "expireat", "flushall", "flushdb", "getset",
+                                                  "hdel", "hincrby", "hmset", "hset", "hsetnx",
+                                                  "incr", "incrby", "incrbyfloat", "linsert",
+                                                  "lpop", "lpush", "lrem", "lset", "ltrim",
+                                                  "mset", "msetnx", "persist", "pexpire",
+                                                  "pexpireat", "psetex", "rename", "renamenx",
+                                                  "rpop", "rpoplpush", "rpush", "sadd", "set",
+                                                  "setex", "setnx", "setrange", "smove", "spop",
+                                                  "srem", "unlink", "zadd", "zincrby", "zrem"];
+
+/// Serve Redis on `host`:`port`, calling `bound` once it's listening.
+pub fn redis_server<KV>(kvstore: KV,
+                        host: &str,
+                        port: u16,
+                        metrics: Arc<Metrics>,
+                        bound: &Fn())
+                        -> result::Result<(), StartupError>
+    where KV: KvStore,
+          KV: Clone,
+          KV: Send,
+          KV: 'static
+{
+    let listener = try!(TcpListener::bind((host, port))
+        .map_err(|e| StartupError::bind("redis", host, port, e)));
+    bound();
+    let connections = ConnectionMetrics::register(PROTOCOL, &metrics);
+
+    // accept connections and process them, spawning a new thread for each one
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                // connection succeeded
+                let kvs = kvstore.clone();
+                let metrics = metrics.clone();
+                let connections = connections.clone();
+                thread::spawn(move || handle_client(kvs, &metrics, &connections, stream));
+            }
+            Err(_) => {
+                trace!("connection failed as it was received");
+            }
+        }
+    }
+    Ok(())
+}
+
+fn handle_client<KV: KvStore>(kvstore: KV,
+                              metrics: &Metrics,
+                              connections: &Arc<ConnectionMetrics>,
+                              stream: TcpStream)
+                              -> Result<()> {
+    let addr = try!(stream.peer_addr());
+    info!("redis connection from {}", addr);
+    let _open = ConnectionMetrics::open(connections);
+    let mut ins = BufReader::new(Counted::new(try!(stream.try_clone()), connections.bytes()));
+    let mut outs = BufWriter::new(Counted::new(stream, connections.bytes()));
+    let result = serve(kvstore, metrics, &mut ins, &mut outs);
+    info!("redis disconnection from {}", addr);
+    result
+}
+
+fn serve<KV: KvStore, T: BufRead>(kvstore: KV,
+                                  metrics: &Metrics,
+                                  ins: &mut T,
+                                  outs: &mut Write)
+                                  -> Result<()> {
+    loop {
+        match Request::parse(ins) {
+            Request::Closed => break,
+            Request::Empty => continue,
+            Request::ProtocolError(msg) => {
+                trace!("redis:protocol error {}", msg);
+                metrics.record(PROTOCOL, "invalid", Outcome::Error);
+                try!(Response::Error(&format!("ERR Protocol error: {}", msg)).write(outs));
+                try!(outs.flush());
+                break;
+            }
+            Request::Command(args) => {
+                let quit = try!(handle_command(&kvstore, metrics, &args, outs));
+                try!(outs.flush());
+                if quit {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Look a key up, counting it in the metrics as a hit or miss of `command`.
+fn lookup<KV: KvStore>(kvstore: &KV,
+                       metrics: &Metrics,
+                       command: &'static str,
+                       key: &[u8])
+                       -> Option<Value> {
+    let value = metrics.time_lookup(PROTOCOL, || kvstore.get(key));
+    metrics.record(PROTOCOL,
+                   command,
+                   if value.is_some() {
+                       Outcome::Hit
+                   } else {
+                       Outcome::Miss
+                   });
+    value
+}
+
+/// Answer one command, returning whether the client asked to quit.
+fn handle_command<KV: KvStore>(kvstore: &KV,
+                               metrics: &Metrics,
+                               args: &[Vec<u8>],
+                               outs: &mut Write)
+                               -> Result<bool> {
+    let name = String::from_utf8_lossy(&args[0]).to_ascii_lowercase();
+    let command = COMMANDS.iter().find(|&&(n, _)| n == name);
+    match command {
+        Some(&(command, arity)) if (arity >= 0 && args.len() as i64 != arity) ||
+                                   (args.len() as i64) < -arity => {
+            trace!("redis:wrong number of arguments for {}", name);
+            metrics.record(PROTOCOL, command, Outcome::Error);
+            try!(Response::Error(&format!("ERR wrong number of arguments for '{}' command",
+                                          name))
+                     .write(outs));
+            return Ok(false);
+        }
+        _ => {}
+    }
+    match name.as_str() {
+        "get" => {
+            trace!("redis:get {:?}", args[1]);
+            let value = lookup(kvstore, metrics, "get", &args[1]);
+            try!(Response::Bulk(value.as_ref().map(|v| &v[..])).write(outs));
+        }
+        "mget" => {
+            trace!("redis:mget {:?}", &args[1..]);
+            try!(Response::ArrayHeader(args.len() - 1).write(outs));
+            for key in args[1..].iter() {
+                let value = lookup(kvstore, metrics, "mget", key);
+                try!(Response::Bulk(value.as_ref().map(|v| &v[..])).write(outs));
+            }
+        }
+        "exists" => {
+            trace!("redis:exists {:?}", &args[1..]);
+            let count = args[1..]
+                .iter()
+                .filter(|key| lookup(kvstore, metrics, "exists", key).is_some())
+                .count();
+            try!(Response::Integer(count as i64).write(outs));
+        }
+        "strlen" => {
+            trace!("redis:strlen {:?}", args[1]);
+            let length = lookup(kvstore, metrics, "strlen", &args[1]).map_or(0, |v| v.len());
+            try!(Response::Integer(length as i64).write(outs));
+        }
+        "ping" => {
+            trace!("redis:ping");
+            metrics.record(PROTOCOL, "ping", Outcome::Ok);
+            match args.get(1) {
+                Some(msg) => try!(Response::Bulk(Some(msg)).write(outs)),
+                None => try!(Response::Status("PONG").write(outs)),
+            }
+        }
+        "echo" => {
+            trace!("redis:echo");
+            metrics.record(PROTOCOL, "echo", Outcome::Ok);
+            try!(Response::Bulk(Some(&args[1])).write(outs));
+        }
+        "quit" => {
+            trace!("redis:quit");
+            metrics.record(PROTOCOL, "quit", Outcome::Ok);
+            try!(Response::Status("OK").write(outs));
+            return Ok(true);
+        }
+        "command" => {
+            trace!("redis:command");
+            let known = try!(write_command_info(args, outs));
+            metrics.record(PROTOCOL,
+                           "command",
+                           if known { Outcome::Ok } else { Outcome::Error });
+        }
+        _ if WRITE_COMMANDS.contains(&name.as_str()) => {
+            trace!("redis:write command {}", name);
+            let command = WRITE_COMMANDS.iter().find(|&&n| n == name).unwrap();
+            metrics.record(PROTOCOL, command, Outcome::Unsupported);
+            try!(Response::Error("READONLY You can't write against a read only server.")
+                     .write(outs));
+        }
+        _ => {
+            trace!("redis:unknown command {}", name);
+            metrics.record(PROTOCOL, "unknown", Outcome::Error);
+            try!(Response::Error(&format!("ERR unknown command '{}'", name)).write(outs));
+        }
+    }
+    Ok(false)
+}
+
+/// Describe our commands in the format of `COMMAND`, or count them for `COMMAND COUNT`.
+/// Returns whether it was a subcommand we know.
+fn write_command_info(args: &[Vec<u8>], outs: &mut Write) -> Result<bool> {
+    let subcommand = args.get(1).map(|s| String::from_utf8_lossy(s).to_ascii_lowercase());
+    match subcommand.as_ref().map(|s| s.as_str()) {
+        None => {}
+        Some("count") => {
+            try!(Response::Integer(COMMANDS.len() as i64).write(outs));
+            return Ok(true);
+        }
+        Some(s) => {
+            try!(Response::Error(&format!("ERR unknown subcommand '{}'", s)).write(outs));
+            return Ok(false);
+        }
+    }
+    try!(Response::ArrayHeader(COMMANDS.len()).write(outs));
+    for &(name, arity) in COMMANDS {
+        let (flags, first_key, last_key, step): (&[&str], i64, i64, i64) = match name {
+            "get" | "strlen" => (&["readonly", "fast"], 1, 1, 1),
+            "mget" | "exists" => (&["readonly", "fast"], 1, -1, 1),
+            _ => (&["fast"], 0, 0, 0),
+        };
+        try!(Response::ArrayHeader(6).write(outs));
+        try!(Response::Bulk(Some(name.as_bytes())).write(outs));
+        try!(Response::Integer(arity).write(outs));
+        try!(Response::ArrayHeader(flags.len()).write(outs));
+        for flag in flags {
+            try!(Response::Status(flag).write(outs));
+        }
+        try!(Response::Integer(first_key).write(outs));
+        try!(Response::Integer(last_key).write(outs));
+        try!(Response::Integer(step).write(outs));
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+
+    use kvstore::{KvStore, Value};
+    use metrics::Metrics;
+    use metrics::connections::ConnectionMetrics;
+
+    /// A KvStore with one pair, {"k": "v"}
+    struct DummyKvStore {
+    }
+
+    impl KvStore for DummyKvStore {
+        fn get(&self, key: &[u8]) -> Option<Value> {
+            if key == "k".as_bytes() {
+                Some(Value::from("v"))
+            } else {
+                None
+            }
+        }
+    }
+
+    fn make_server_conn(metrics: Arc<Metrics>) -> TcpStream {
+        let listener = TcpListener::bind(("localhost", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client_conn = TcpStream::connect(("localhost", port)).unwrap();
+        thread::spawn(move || {
+            let (server_stream, _) = listener.accept().unwrap();
+            let connections = ConnectionMetrics::register("redis", &metrics);
+            super::handle_client(DummyKvStore {}, &metrics, &connections, server_stream)
+                .unwrap_or(());
+        });
+        client_conn
+    }
+
+    fn request_with_metrics(req: &str, metrics: Arc<Metrics>) -> String {
+        let mut client_stream = make_server_conn(metrics);
+        client_stream.write(req.as_bytes()).unwrap();
+        client_stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client_stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn request(req: &str) -> String {
+        request_with_metrics(req, Arc::new(Metrics::new()))
+    }
+
+    #[test]
+    fn test_get() {
+        assert_eq!("$1\r\nv\r\n$-1\r\n",
+                   request("*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*2\r\n$3\r\nGET\r\n$1\r\n_\r\n"));
+    }
+
+    #[test]
+    fn test_inline() {
+        assert_eq!("+PONG\r\n$1\r\nv\r\n", request("PING\r\n\r\nget k\r\n"));
+    }
+
+    #[test]
+    fn test_mget_exists_strlen() {
+        assert_eq!("*2\r\n$1\r\nv\r\n$-1\r\n:2\r\n:1\r\n:0\r\n",
+                   request("MGET k _\r\nEXISTS k k _\r\nSTRLEN k\r\nSTRLEN _\r\n"));
+    }
+
+    #[test]
+    fn test_echo_quit() {
+        // Nothing after QUIT is answered.
+        assert_eq!("$2\r\nhi\r\n+OK\r\n", request("ECHO hi\r\nQUIT\r\nPING\r\n"));
+    }
+
+    #[test]
+    fn test_read_only() {
+        // Write commands get an error, but the connection stays usable.
+        assert_eq!("-READONLY You can't write against a read only server.\r\n+PONG\r\n",
+                   request("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nx\r\nPING\r\n"));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!("-ERR wrong number of arguments for 'get' command\r\n\
+                    -ERR unknown command 'frob'\r\n",
+                   request("GET\r\nFROB\r\n"));
+        // Protocol errors close the connection.
+        assert_eq!("-ERR Protocol error: expected '$'\r\n",
+                   request("*1\r\n+GET\r\nPING\r\n"));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        request_with_metrics("MGET k _\r\nSET k x\r\nFROB\r\nPING\r\n", metrics.clone());
+        let rendered = metrics.render();
+        for line in &["cdbd_requests_total{protocol=\"redis\",command=\"mget\",\
+                       outcome=\"hit\"} 1\n",
+                      "cdbd_requests_total{protocol=\"redis\",command=\"mget\",\
+                       outcome=\"miss\"} 1\n",
+                      "cdbd_requests_total{protocol=\"redis\",command=\"set\",\
+                       outcome=\"unsupported\"} 1\n",
+                      "cdbd_requests_total{protocol=\"redis\",command=\"unknown\",\
+                       outcome=\"error\"} 1\n",
+                      "cdbd_requests_total{protocol=\"redis\",command=\"ping\",\
+                       outcome=\"ok\"} 1\n",
+                      "cdbd_lookup_duration_seconds_count{protocol=\"redis\"} 2\n",
+                      "cdbd_connections_total{protocol=\"redis\"} 1\n",
+                      "cdbd_read_bytes_total{protocol=\"redis\"} 31\n"] {
+            assert!(rendered.contains(line), "{:?} missing from {}", line, rendered);
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::sync::Mutex;
+
+use futures::Future;
+use futures::future::Shared;
+use futures::sync::oneshot;
+
+/// Tells services to stop taking new work and finish what they have
+pub struct Shutdown {
+    sender: Mutex<Option<oneshot::Sender<()>>>,
+    started: Shared<oneshot::Receiver<()>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        let (sender, receiver) = oneshot::channel();
+        Shutdown {
+            sender: Mutex::new(Some(sender)),
+            started: receiver.shared(),
+        }
+    }
+
+    /// Start shutting down.
+    pub fn begin(&self) {
+        if let Some(sender) = self.sender.lock().unwrap().take() {
+            let _ = sender.send(());
+        }
+    }
+
+    /// A future that succeeds once shutdown has begun
+    pub fn started(&self) -> Shared<oneshot::Receiver<()>> {
+        self.started.clone()
+    }
+}
@@ -0,0 +1,77 @@
+//! Why cdbd failed to start, and the exit status saying so
+
+use std::fmt;
+use std::io;
+
+use config::ConfigError;
+
+/// The exit status for invalid flags
+pub const EXIT_ARGS: i32 = 2;
+/// The exit status for an invalid config file
+pub const EXIT_CONFIG: i32 = 3;
+/// The exit status for a database that couldn't be opened
+pub const EXIT_OPEN: i32 = 4;
+/// The exit status for a service that couldn't listen on its address
+pub const EXIT_BIND: i32 = 5;
+/// The exit status for failing to set up anything else, like signal handling
+pub const EXIT_SETUP: i32 = 6;
+
+/// Why cdbd failed to start
+#[derive(Debug)]
+pub enum StartupError {
+    /// The flags were invalid
+    Args(String),
+    /// The config file at this path was invalid
+    Config(String, ConfigError),
+    /// The database couldn't be opened
+    Open(io::Error),
+    /// A service couldn't listen on its address
+    Bind {
+        service: &'static str,
+        address: String,
+        error: io::Error,
+    },
+    /// Something else we need couldn't be set up
+    Setup(String),
+}
+
+impl StartupError {
+    /// A service's failure to listen on `host`:`port`
+    pub fn bind(service: &'static str, host: &str, port: u16, error: io::Error) -> StartupError {
+        StartupError::Bind {
+            service: service,
+            address: format!("{}:{}", host, port),
+            error: error,
+        }
+    }
+
+    /// A failure to set something up, described as what we failed to do
+    pub fn setup<E: fmt::Display>(what: &str, error: E) -> StartupError {
+        StartupError::Setup(format!("failed to {}: {}", what, error))
+    }
+
+    /// The status to exit with, which differs for each kind of failure
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            &StartupError::Args(_) => EXIT_ARGS,
+            &StartupError::Config(..) => EXIT_CONFIG,
+            &StartupError::Open(_) => EXIT_OPEN,
+            &StartupError::Bind { .. } => EXIT_BIND,
+            &StartupError::Setup(_) => EXIT_SETUP,
+        }
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &StartupError::Args(ref message) => write!(f, "{}", message),
+            &StartupError::Config(ref path, ref e) => write!(f, "error in {}: {}", path, e),
+            &StartupError::Open(ref e) => write!(f, "failed to open database: {}", e),
+            &StartupError::Bind { service, ref address, ref error } => {
+                write!(f, "failed to serve {} on {}: {}", service, address, error)
+            }
+            &StartupError::Setup(ref message) => write!(f, "{}", message),
+        }
+    }
+}